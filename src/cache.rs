@@ -0,0 +1,363 @@
+use crate::error::Error;
+use rocket::serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Storage used to cache node responses and to keep the address index.
+/// Besides plain string values (mostly JSON) the address index needs
+/// field maps (one per transaction) and scored sets (one per address).
+#[async_trait::async_trait]
+pub trait CacheBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, Error>;
+    /// Store a value, expiring after `ttl` when given. It is never evicted.
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error>;
+    /// Store a cached node response, which can be evicted to stay within the size budget
+    async fn cache(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error>;
+    async fn exists(&self, key: &str) -> Result<bool, Error>;
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+    /// Remaining time to live of a value, None when it never expires or does not exist
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, Error>;
+    /// Apply the operations in order, in a single round trip and atomically
+    /// where the backend supports it
+    async fn batch(&self, ops: Vec<CacheOp>) -> Result<(), Error>;
+    /// Fields of the maps stored at keys, missing keys give an empty map
+    async fn fields(&self, keys: &[String]) -> Result<Vec<HashMap<String, String>>, Error>;
+    /// Members of the scored set at key with a score between min and max (inclusive),
+    /// skipping `offset` members and returning at most `count`
+    async fn range_by_score(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+        ascending: bool,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, Error>;
+    /// Amount of members of the scored set at key with a score between min and max (inclusive)
+    async fn count_by_score(&self, key: &str, min: f64, max: f64) -> Result<usize, Error>;
+    /// Every key starting with prefix
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error>;
+    /// Size and evictions of the backend
+    async fn stats(&self) -> Result<BackendStats, Error>;
+    /// Periodic housekeeping, such as dropping expired values and enforcing the size budget.
+    /// Only the values stored with `cache` count towards the budget and get evicted.
+    async fn maintain(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Size of a backend as reported by `CacheBackend::stats`
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BackendStats {
+    pub keys: u64,
+    /// Approximate size of the stored keys and values
+    pub bytes: u64,
+    /// Approximate size of the cached node responses, the part the size budget applies to
+    pub cached_bytes: u64,
+    /// Keys dropped to stay within the size budget
+    pub evicted: u64,
+}
+
+/// A write queued in a `CacheBackend::batch`
+#[derive(Debug, Clone)]
+pub enum CacheOp {
+    Set {
+        key: String,
+        value: String,
+        ttl: Option<Duration>,
+    },
+    Delete {
+        key: String,
+    },
+    /// Set fields of the map at key, other fields are kept
+    SetFields {
+        key: String,
+        fields: Vec<(String, String)>,
+    },
+    /// Add a member to the scored set at key, or update its score
+    AddScored {
+        key: String,
+        member: String,
+        score: f64,
+    },
+    /// Remove a member from the scored set at key
+    RemoveScored {
+        key: String,
+        member: String,
+    },
+}
+
+/// Default amount of blocks on top of a block before it is treated as final
+pub const DEFAULT_CONFIRMATIONS: u64 = 12;
+
+/// ENS lookups kept in process when the cache is disabled
+const NAMES_CAPACITY: usize = 10_000;
+
+/// Available `CacheBackend` implementations
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq)]
+pub enum BackendKind {
+    /// External Redis server
+    Redis,
+    /// Bounded in-process LRU, lost on restart
+    Memory,
+    /// Embedded on-disk store
+    Disk,
+}
+
+/// How long cached data stays valid, depending on how likely it is to change
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Category {
+    /// Immutable data: final blocks and transactions, anything keyed by a block hash
+    Final,
+    /// Blocks and transactions that a reorg can still replace
+    Recent,
+    /// Mutable account state such as balances and nonces
+    Account,
+    /// ENS names, owners and records
+    Ens,
+}
+
+/// Expiry of each `Category` and size budget of the cache
+#[derive(clap::Args, Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CachePolicy {
+    /// Seconds final data is kept, 0 to keep it until evicted
+    #[clap(long, default_value_t = 0)]
+    pub ttl_final: u64,
+    /// Seconds recent blocks and transactions are kept
+    #[clap(long, default_value_t = 30)]
+    pub ttl_recent: u64,
+    /// Seconds balances and nonces are kept, 0 to not cache them
+    #[clap(long, default_value_t = 5)]
+    pub ttl_account: u64,
+    /// Seconds ENS names and records are kept, 0 to not cache them
+    #[clap(long, default_value_t = 3600)]
+    pub ttl_ens: u64,
+    /// Size budget of the cached node responses in bytes, 0 for no limit.
+    /// The memory backend evicts the least recently used responses first, the disk
+    /// and Redis backends the least recently written ones. The address index,
+    /// the crawler state and user data are never evicted.
+    #[clap(long = "cache-max-bytes", default_value_t = 0)]
+    pub max_bytes: u64,
+}
+
+impl CachePolicy {
+    /// Expiry of a category, None to keep the data until evicted
+    pub fn ttl(&self, category: Category) -> Option<Duration> {
+        match category {
+            Category::Final => (self.ttl_final > 0).then(|| Duration::from_secs(self.ttl_final)),
+            Category::Recent => Some(Duration::from_secs(self.ttl_recent)),
+            Category::Account => Some(Duration::from_secs(self.ttl_account)),
+            Category::Ens => Some(Duration::from_secs(self.ttl_ens)),
+        }
+    }
+
+    /// Whether data of a category is cached at all
+    pub fn caches(&self, category: Category) -> bool {
+        self.ttl(category) != Some(Duration::ZERO)
+    }
+}
+
+impl Default for CachePolicy {
+    fn default() -> CachePolicy {
+        CachePolicy {
+            ttl_final: 0,
+            ttl_recent: 30,
+            ttl_account: 5,
+            ttl_ens: 3600,
+            max_bytes: 0,
+        }
+    }
+}
+
+/// Cache state returned by the admin stats endpoint
+#[derive(Debug, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub backend: Option<String>,
+    pub policy: CachePolicy,
+    pub confirmations: u64,
+    pub head: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub storage: Option<BackendStats>,
+}
+
+/// Hash of the canonical block at a height, only kept for final blocks
+/// and for the recent blocks followed by `reorg::track_head`
+pub fn canonical_key(block_number: u64) -> String {
+    format!("canonical_{}", block_number)
+}
+
+/// The cache shared by the pages, the api, the poller and the crawler
+#[derive(Clone)]
+pub struct Cache {
+    pub enabled: bool,
+    backend: Option<Arc<dyn CacheBackend>>,
+    kind: Option<BackendKind>,
+    pub policy: CachePolicy,
+    /// Blocks less than this deep below the head can still be reorged
+    pub confirmations: u64,
+    /// Highest block number seen from the node
+    head: Arc<AtomicU64>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+    /// ENS lookups kept in process when the cache is disabled, pages show hundreds of addresses
+    names: Arc<crate::lrucache::LruCache>,
+}
+
+impl Cache {
+    pub fn new(backend: Arc<dyn CacheBackend>) -> Cache {
+        Cache {
+            enabled: true,
+            backend: Some(backend),
+            kind: None,
+            policy: CachePolicy::default(),
+            confirmations: DEFAULT_CONFIRMATIONS,
+            head: Arc::new(AtomicU64::new(0)),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            names: Arc::new(crate::lrucache::LruCache::new(NAMES_CAPACITY, 0)),
+        }
+    }
+
+    /// No cache, only the ENS lookups are kept in process for the `policy` ENS expiry
+    pub fn disabled(policy: CachePolicy) -> Cache {
+        Cache {
+            enabled: false,
+            backend: None,
+            kind: None,
+            policy,
+            confirmations: DEFAULT_CONFIRMATIONS,
+            head: Arc::new(AtomicU64::new(0)),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+            names: Arc::new(crate::lrucache::LruCache::new(NAMES_CAPACITY, 0)),
+        }
+    }
+
+    pub fn with_confirmations(mut self, confirmations: u64) -> Cache {
+        self.confirmations = confirmations;
+        self
+    }
+
+    /// Record a head block number returned by the node
+    pub fn observe_head(&self, block_number: u64) {
+        self.head.fetch_max(block_number, Ordering::Relaxed);
+    }
+
+    /// Whether a block is deep enough below the last seen head to be cached by number.
+    /// Nothing is final until a head has been seen.
+    pub fn is_final(&self, block_number: u64) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        head != 0 && block_number.saturating_add(self.confirmations) <= head
+    }
+
+    /// Open the selected backend.
+    /// `capacity` bounds the memory backend, `path` is the directory of the disk backend.
+    pub async fn open(
+        kind: BackendKind,
+        capacity: usize,
+        path: &str,
+        redis: &crate::rcache::RedisConfig,
+        policy: CachePolicy,
+    ) -> Result<Cache, Error> {
+        let max_bytes = policy.max_bytes;
+        let backend: Arc<dyn CacheBackend> = match kind {
+            BackendKind::Redis => {
+                Arc::new(crate::rcache::RedisCache::open(redis, max_bytes).await?)
+            }
+            BackendKind::Memory => {
+                Arc::new(crate::lrucache::LruCache::new(capacity, max_bytes as usize))
+            }
+            BackendKind::Disk => Arc::new(crate::diskcache::DiskCache::open(path, max_bytes)?),
+        };
+        let mut cache = Cache::new(backend);
+        cache.kind = Some(kind);
+        cache.policy = policy;
+        Ok(cache)
+    }
+
+    /// The backend, fails when the cache is disabled
+    pub fn backend(&self) -> Result<&dyn CacheBackend, Error> {
+        self.backend
+            .as_deref()
+            .ok_or_else(|| Error::Cache("Cache is not enabled".to_string()))
+    }
+
+    /// Cached value at key, None when missing or when the cache is disabled
+    pub async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let value = self.backend()?.get(key).await?;
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(value)
+    }
+
+    /// Cache a value with the expiry of its category, does nothing when the cache is disabled
+    pub async fn set(&self, key: &str, value: &str, category: Category) -> Result<(), Error> {
+        if !self.enabled || !self.policy.caches(category) {
+            return Ok(());
+        }
+        self.backend()?
+            .cache(key, value, self.policy.ttl(category))
+            .await
+    }
+
+    /// Cached ENS lookup at key, read in process when the cache is disabled
+    pub async fn get_ens(&self, key: &str) -> Result<Option<String>, Error> {
+        if self.enabled {
+            self.get(key).await
+        } else {
+            self.names.get(key).await
+        }
+    }
+
+    /// Cache an ENS lookup, in process when the cache is disabled
+    pub async fn set_ens(&self, key: &str, value: &str) -> Result<(), Error> {
+        if self.enabled {
+            return self.set(key, value, Category::Ens).await;
+        }
+        if !self.policy.caches(Category::Ens) {
+            return Ok(());
+        }
+        self.names
+            .cache(key, value, self.policy.ttl(Category::Ens))
+            .await
+    }
+
+    /// Like `set`, for values other code relies on: they never expire and are never evicted
+    pub async fn set_pinned(&self, key: &str, value: &str) -> Result<(), Error> {
+        if !self.enabled {
+            return Ok(());
+        }
+        self.backend()?.set(key, value, None).await
+    }
+
+    pub async fn stats(&self) -> Result<CacheStats, Error> {
+        let storage = match &self.backend {
+            Some(backend) => Some(backend.stats().await?),
+            None => None,
+        };
+        Ok(CacheStats {
+            enabled: self.enabled,
+            backend: self.kind.map(|kind| format!("{:?}", kind).to_lowercase()),
+            policy: self.policy.clone(),
+            confirmations: self.confirmations,
+            head: self.head.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            storage,
+        })
+    }
+}
@@ -1,29 +1,585 @@
-pub async fn crawler(s_b: i64, e_b: i64) {
+use crate::cache::CacheOp;
+use crate::error::Error;
+use futures::stream::{self, StreamExt};
+use rocket::serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Which way the crawler walks the chain
+#[derive(clap::ArgEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From `--start-block` up to `--end-block`
+    Forward,
+    /// From `--start-block` (the head by default) down to `--end-block` (genesis by default)
+    Backward,
+}
+
+/// What to crawl and how
+#[derive(Debug, Clone)]
+pub struct CrawlOptions {
+    pub start_block: Option<i64>,
+    pub end_block: Option<i64>,
+    pub direction: Direction,
+    /// Blocks indexed at the same time
+    pub concurrency: usize,
+    /// Passes over the failed blocks before giving up until the next run
+    pub retries: u32,
+}
+
+impl CrawlOptions {
+    /// Check that the blocks, when given, are not negative and that the end block
+    /// lies ahead of the start block
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(block) = [self.start_block, self.end_block]
+            .into_iter()
+            .flatten()
+            .find(|block| *block < 0)
+        {
+            return Err(Error::Parse(format!("Invalid block number {}", block)));
+        }
+        let wrong_order = match (self.start_block, self.end_block) {
+            (Some(start), Some(end)) => match self.direction {
+                Direction::Forward => end < start,
+                Direction::Backward => end > start,
+            },
+            _ => false,
+        };
+        if wrong_order {
+            return Err(Error::Parse(
+                "Ending block must be past the starting block in the crawl direction".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Progress of a crawl, saved after every chunk of blocks so a restart resumes it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Checkpoint {
+    pub direction: Direction,
+    pub start: i64,
+    pub end: i64,
+    /// Next block to index
+    pub next: i64,
+}
+
+impl Checkpoint {
+    fn done(&self) -> bool {
+        match self.direction {
+            Direction::Forward => self.next > self.end,
+            Direction::Backward => self.next < self.end,
+        }
+    }
+
+    /// Up to `size` blocks from `next`, in crawl order
+    fn chunk(&self, size: i64) -> Vec<i64> {
+        match self.direction {
+            Direction::Forward => (self.next..=self.end.min(self.next + size - 1)).collect(),
+            Direction::Backward => (self.end.max(self.next - size + 1)..=self.next)
+                .rev()
+                .collect(),
+        }
+    }
+}
+
+/// Crawl progress, one per direction so a backward crawl and a forward one can coexist
+fn checkpoint_key(direction: Direction) -> String {
+    match direction {
+        Direction::Forward => "crawler_checkpoint_forward".to_string(),
+        Direction::Backward => "crawler_checkpoint_backward".to_string(),
+    }
+}
+
+/// Scored set of the blocks that failed to be indexed, scored by block number
+const GAPS_KEY: &str = "crawler_gaps";
+
+/// Most workers of a crawl started from the admin endpoints
+pub const MAX_CONCURRENCY: usize = 32;
+
+/// Blocks handed to the workers between two checkpoints, per worker
+const BLOCKS_PER_WORKER: i64 = 4;
+
+/// Failed blocks retried per pass
+const GAP_RETRY_BATCH: usize = 1000;
+
+/// Seconds to wait before retrying failed blocks
+const GAP_RETRY_DELAY: u64 = 5;
+
+/// Where a crawl stands
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum CrawlState {
+    Idle,
+    Running,
+    Paused,
+    Cancelled,
+    Finished,
+    Failed,
+}
+
+/// Progress of the current or last crawl, as returned by the status endpoint
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CrawlStatus {
+    pub state: CrawlState,
+    pub direction: Option<Direction>,
+    pub start_block: Option<i64>,
+    pub end_block: Option<i64>,
+    /// Last block indexed
+    pub current_block: Option<i64>,
+    pub indexed: u64,
+    pub errors: u64,
+    pub last_error: Option<String>,
+    pub blocks_per_second: f64,
+    pub eta_seconds: Option<u64>,
+    /// Ranges indexed by this crawl, `[low, high]` inclusive and sorted
+    pub completed_ranges: Vec<[i64; 2]>,
+    /// Failed blocks waiting to be retried
+    pub gaps: usize,
+}
+
+struct Progress {
+    state: CrawlState,
+    checkpoint: Option<Checkpoint>,
+    current_block: Option<i64>,
+    indexed: u64,
+    errors: u64,
+    last_error: Option<String>,
+    completed_ranges: Vec<[i64; 2]>,
+    gaps: usize,
+    /// Running time before the last resume, pauses excluded
+    active: Duration,
+    running_since: Option<Instant>,
+}
+
+impl Progress {
+    fn new(state: CrawlState) -> Progress {
+        Progress {
+            state,
+            checkpoint: None,
+            current_block: None,
+            indexed: 0,
+            errors: 0,
+            last_error: None,
+            completed_ranges: vec![],
+            gaps: 0,
+            active: Duration::ZERO,
+            running_since: None,
+        }
+    }
+
+    /// Stop the running time, on pause and when the crawl ends
+    fn stop_clock(&mut self) {
+        if let Some(since) = self.running_since.take() {
+            self.active += since.elapsed();
+        }
+    }
+
+    /// Add a block to the completed ranges, merging it with its neighbours
+    fn complete(&mut self, n: i64) {
+        let ranges = &mut self.completed_ranges;
+        let at = ranges.partition_point(|r| r[1] < n - 1);
+        if at < ranges.len() && ranges[at][0] <= n + 1 {
+            ranges[at] = [ranges[at][0].min(n), ranges[at][1].max(n)];
+            // Filling the hole between two ranges joins them
+            if at + 1 < ranges.len() && ranges[at + 1][0] <= ranges[at][1] + 1 {
+                ranges[at][1] = ranges[at + 1][1];
+                ranges.remove(at + 1);
+            }
+        } else {
+            ranges.insert(at, [n, n]);
+        }
+    }
+
+    fn status(&self) -> CrawlStatus {
+        let elapsed = self.active + self.running_since.map_or(Duration::ZERO, |s| s.elapsed());
+        let blocks_per_second = if elapsed.as_secs_f64() > 0.0 {
+            self.indexed as f64 / elapsed.as_secs_f64()
+        } else {
+            0.0
+        };
+        let remaining = self
+            .checkpoint
+            .as_ref()
+            .filter(|c| !c.done())
+            .map_or(0, |c| (c.end - c.next).unsigned_abs() + 1);
+        let eta_seconds = match self.state {
+            CrawlState::Running | CrawlState::Paused if blocks_per_second > 0.0 => {
+                Some((remaining as f64 / blocks_per_second) as u64)
+            }
+            _ => None,
+        };
+        CrawlStatus {
+            state: self.state,
+            direction: self.checkpoint.as_ref().map(|c| c.direction),
+            start_block: self.checkpoint.as_ref().map(|c| c.start),
+            end_block: self.checkpoint.as_ref().map(|c| c.end),
+            current_block: self.current_block,
+            indexed: self.indexed,
+            errors: self.errors,
+            last_error: self.last_error.clone(),
+            blocks_per_second,
+            eta_seconds,
+            completed_ranges: self.completed_ranges.clone(),
+            gaps: self.gaps,
+        }
+    }
+}
+
+/// Runs one crawl at a time in the background and lets the admin endpoints
+/// follow, pause, resume and cancel it
+#[derive(Clone)]
+pub struct CrawlerControl {
+    /// Used for what a start request leaves out
+    pub defaults: CrawlOptions,
+    progress: Arc<Mutex<Progress>>,
+    paused: Arc<AtomicBool>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CrawlerControl {
+    pub fn new(defaults: CrawlOptions) -> CrawlerControl {
+        CrawlerControl {
+            defaults,
+            progress: Arc::new(Mutex::new(Progress::new(CrawlState::Idle))),
+            paused: Arc::new(AtomicBool::new(false)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn status(&self) -> CrawlStatus {
+        self.progress.lock().unwrap().status()
+    }
+
+    /// Start a crawl in the background, fails when one is already running or paused
+    pub fn start(
+        &self,
+        client: crate::EtherClient,
+        cache: crate::Cache,
+        options: CrawlOptions,
+    ) -> Result<(), Error> {
+        if !cache.enabled {
+            return Err(Error::Cache("The crawler needs the cache".to_string()));
+        }
+        options.validate()?;
+        {
+            let mut progress = self.progress.lock().unwrap();
+            if matches!(progress.state, CrawlState::Running | CrawlState::Paused) {
+                return Err(Error::Conflict(
+                    "A crawl is already in progress".to_string(),
+                ));
+            }
+            *progress = Progress::new(CrawlState::Running);
+            progress.running_since = Some(Instant::now());
+        }
+        self.paused.store(false, Ordering::Relaxed);
+        self.cancelled.store(false, Ordering::Relaxed);
+
+        let control = self.clone();
+        rocket::tokio::spawn(async move {
+            let result = crawler(&client, &cache, &options, &control).await;
+            let mut progress = control.progress.lock().unwrap();
+            progress.stop_clock();
+            progress.state = match result {
+                Ok(()) if control.cancelled.load(Ordering::Relaxed) => CrawlState::Cancelled,
+                Ok(()) => CrawlState::Finished,
+                Err(e) => {
+                    println!("Crawler failed: {}", e);
+                    progress.last_error = Some(e.to_string());
+                    CrawlState::Failed
+                }
+            };
+        });
+        Ok(())
+    }
+
+    pub fn pause(&self) -> Result<(), Error> {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.state != CrawlState::Running {
+            return Err(Error::Conflict("No crawl is running".to_string()));
+        }
+        self.paused.store(true, Ordering::Relaxed);
+        progress.state = CrawlState::Paused;
+        progress.stop_clock();
+        Ok(())
+    }
+
+    pub fn resume(&self) -> Result<(), Error> {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.state != CrawlState::Paused {
+            return Err(Error::Conflict("No crawl is paused".to_string()));
+        }
+        self.paused.store(false, Ordering::Relaxed);
+        progress.state = CrawlState::Running;
+        progress.running_since = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Stop the crawl after the blocks in progress, its checkpoint is dropped
+    pub fn cancel(&self) -> Result<(), Error> {
+        let progress = self.progress.lock().unwrap();
+        if !matches!(progress.state, CrawlState::Running | CrawlState::Paused) {
+            return Err(Error::Conflict("No crawl is in progress".to_string()));
+        }
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.paused.store(false, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Wait while the crawl is paused, false once it is cancelled
+    async fn proceed(&self) -> bool {
+        while self.paused.load(Ordering::Relaxed) && !self.cancelled.load(Ordering::Relaxed) {
+            rocket::tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        !self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, block_number: i64, result: &Result<(), Error>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.current_block = Some(block_number);
+        match result {
+            Ok(()) => {
+                progress.indexed += 1;
+                progress.complete(block_number);
+            }
+            Err(e) => {
+                progress.errors += 1;
+                progress.last_error = Some(format!("Block {}: {}", block_number, e));
+            }
+        }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Progress)) {
+        f(&mut self.progress.lock().unwrap())
+    }
+}
+
+/// Index the transactions of past blocks with a pool of workers.
+///
+/// The range comes from the options, or from the saved checkpoint of the same direction
+/// when one matches them, so an interrupted crawl carries on where it stopped.
+/// Blocks that fail are recorded as gaps and retried at the end of the crawl,
+/// and again on the next run if they still fail.
+pub async fn crawler(
+    client: &crate::EtherClient,
+    cache: &crate::Cache,
+    options: &CrawlOptions,
+    control: &CrawlerControl,
+) -> Result<(), Error> {
     println!("Starting tx crawler");
 
     let block_number = &crate::parser::parse_request(
+        client,
+        cache,
         "eth",
         "blockNumber",
         crate::RequestData {
             data: serde_json::json!({}),
         },
     )
-    .await
+    .await?
     .data["blockNumber"];
-    let b_n = crate::json_value_hex_to_int(block_number.clone());
-    let latest_block: i64 = b_n as i64;
-    let mut starting_block = s_b;
-    if starting_block == 0 {
-        starting_block = latest_block - 100;
-    }
+    let latest_block = crate::json_value_hex_to_int(block_number.clone())? as i64;
+
+    let mut checkpoint = resume_or_start(cache, options, latest_block).await?;
+    let concurrency = options.concurrency.max(1);
+    println!(
+        "Crawling {:?} from block {} to block {}, {} workers",
+        checkpoint.direction, checkpoint.next, checkpoint.end, concurrency
+    );
+    let backend = cache.backend()?;
+    let gaps = backend.count_by_score(GAPS_KEY, 0.0, f64::MAX).await?;
+    control.update(|p| {
+        p.checkpoint = Some(checkpoint.clone());
+        p.gaps = gaps;
+    });
 
-    for i in starting_block..=latest_block {
-        println!("Crawling tx from block {}", i);
-        if i >= e_b {
-            break;
+    while !checkpoint.done() {
+        if !control.proceed().await {
+            println!("Crawl cancelled at block {}", checkpoint.next);
+            backend
+                .delete(&checkpoint_key(checkpoint.direction))
+                .await?;
+            return Ok(());
         }
-        let _ = crate::pages::address::cache_addresses_transactions_from_block(i).await;
+
+        let blocks = checkpoint.chunk(concurrency as i64 * BLOCKS_PER_WORKER);
+        let failed = index_blocks(client, cache, &blocks, concurrency, control).await;
+
+        let mut ops: Vec<CacheOp> = failed
+            .iter()
+            .map(|n| CacheOp::AddScored {
+                key: GAPS_KEY.to_string(),
+                member: n.to_string(),
+                score: *n as f64,
+            })
+            .collect();
+        checkpoint.next = match checkpoint.direction {
+            Direction::Forward => blocks[blocks.len() - 1] + 1,
+            Direction::Backward => blocks[blocks.len() - 1] - 1,
+        };
+        ops.push(CacheOp::Set {
+            key: checkpoint_key(checkpoint.direction),
+            value: serde_json::to_string(&checkpoint)?,
+            ttl: None,
+        });
+        backend.batch(ops).await?;
+
+        let gaps = backend.count_by_score(GAPS_KEY, 0.0, f64::MAX).await?;
+        control.update(|p| {
+            p.checkpoint = Some(checkpoint.clone());
+            p.gaps = gaps;
+        });
     }
 
+    retry_gaps(client, cache, concurrency, options.retries, control).await?;
+
     println!("Finished tx crawler");
+    Ok(())
+}
+
+/// The saved checkpoint when it is unfinished and matches the options, a new one otherwise
+async fn resume_or_start(
+    cache: &crate::Cache,
+    options: &CrawlOptions,
+    latest_block: i64,
+) -> Result<Checkpoint, Error> {
+    let saved: Option<Checkpoint> = match cache
+        .backend()?
+        .get(&checkpoint_key(options.direction))
+        .await?
+    {
+        Some(saved) => Some(serde_json::from_str(&saved)?),
+        None => None,
+    };
+    if let Some(saved) = saved {
+        let matches = options.start_block.is_none_or(|s| s == saved.start)
+            && options.end_block.is_none_or(|e| e == saved.end);
+        if matches && !saved.done() {
+            println!("Resuming crawl at block {}", saved.next);
+            return Ok(saved);
+        }
+    }
+
+    let (start, end) = match options.direction {
+        Direction::Forward => (
+            options
+                .start_block
+                .unwrap_or_else(|| (latest_block - 100).max(0)),
+            options.end_block.unwrap_or(latest_block),
+        ),
+        Direction::Backward => (
+            options.start_block.unwrap_or(latest_block),
+            options.end_block.unwrap_or(0),
+        ),
+    };
+    Ok(Checkpoint {
+        direction: options.direction,
+        start,
+        end,
+        next: start,
+    })
+}
+
+/// Index blocks with `concurrency` workers, returns the blocks that failed
+async fn index_blocks(
+    client: &crate::EtherClient,
+    cache: &crate::Cache,
+    blocks: &[i64],
+    concurrency: usize,
+    control: &CrawlerControl,
+) -> Vec<i64> {
+    stream::iter(blocks.to_vec())
+        .map(|n| async move {
+            println!("Crawling tx from block {}", n);
+            let result =
+                crate::pages::address::cache_addresses_transactions_from_block(client, cache, n)
+                    .await;
+            control.record(n, &result);
+            match result {
+                Ok(()) => None,
+                Err(e) => {
+                    println!("Failed to crawl block {}: {}", n, e);
+                    Some(n)
+                }
+            }
+        })
+        .buffer_unordered(concurrency)
+        .filter_map(|failed| async move { failed })
+        .collect()
+        .await
+}
+
+/// Index the recorded gaps again, removing the blocks that succeed
+async fn retry_gaps(
+    client: &crate::EtherClient,
+    cache: &crate::Cache,
+    concurrency: usize,
+    retries: u32,
+    control: &CrawlerControl,
+) -> Result<(), Error> {
+    let backend = cache.backend()?;
+    for attempt in 1..=retries {
+        let gaps: Vec<i64> = backend
+            .range_by_score(GAPS_KEY, 0.0, f64::MAX, true, 0, GAP_RETRY_BATCH)
+            .await?
+            .iter()
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        if gaps.is_empty() {
+            return Ok(());
+        }
+        println!(
+            "Retrying {} failed blocks, attempt {}/{}",
+            gaps.len(),
+            attempt,
+            retries
+        );
+        rocket::tokio::time::sleep(Duration::from_secs(GAP_RETRY_DELAY)).await;
+        if !control.proceed().await {
+            return Ok(());
+        }
+
+        let failed = index_blocks(client, cache, &gaps, concurrency, control).await;
+        let ops = gaps
+            .iter()
+            .filter(|n| !failed.contains(n))
+            .map(|n| CacheOp::RemoveScored {
+                key: GAPS_KEY.to_string(),
+                member: n.to_string(),
+            })
+            .collect();
+        backend.batch(ops).await?;
+        let left = backend.count_by_score(GAPS_KEY, 0.0, f64::MAX).await?;
+        control.update(|p| p.gaps = left);
+    }
+
+    let left = backend.count_by_score(GAPS_KEY, 0.0, f64::MAX).await?;
+    if left > 0 {
+        println!(
+            "{} blocks could not be crawled, retrying on the next run",
+            left
+        );
+    }
+    Ok(())
+}
+
+/// Record blocks indexed by nobody as gaps, the next crawl indexes them
+pub async fn add_gaps(
+    cache: &crate::Cache,
+    blocks: std::ops::RangeInclusive<u64>,
+) -> Result<(), Error> {
+    let ops = blocks
+        .map(|n| CacheOp::AddScored {
+            key: GAPS_KEY.to_string(),
+            member: n.to_string(),
+            score: n as f64,
+        })
+        .collect();
+    cache.backend()?.batch(ops).await
 }
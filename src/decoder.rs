@@ -0,0 +1,388 @@
+use crate::error::Error;
+use rocket::serde::Serialize;
+use std::collections::HashMap;
+use web3::ethabi;
+use web3::types::{H256, U256};
+
+/// ABIs bundled with etherglass, more can be registered with --abi-dir
+const BUILTIN_ABIS: [(&str, &str); 3] = [
+    ("ERC-20", include_str!("../abi/erc20.json")),
+    ("ERC-721", include_str!("../abi/erc721.json")),
+    ("ERC-1155", include_str!("../abi/erc1155.json")),
+];
+
+/// Offline text signature database used when no ABI knows a selector
+const SIGNATURE_DATABASE: &str = include_str!("../abi/signatures.txt");
+
+/// Source name of functions coming from the signature database
+const SIGNATURE_DATABASE_NAME: &str = "signature database";
+
+/// Known event and function signatures used to decode logs and calldata
+pub struct AbiRegistry {
+    /// Events by topic0, several ABIs can share the same signature
+    /// (e.g. ERC-20 and ERC-721 Transfer differ only by indexed params)
+    events: HashMap<H256, Vec<(String, ethabi::Event)>>,
+    /// Functions by 4-byte selector, ABIs first then the signature database
+    functions: HashMap<[u8; 4], Vec<FunctionSignature>>,
+}
+
+/// A function known by its inputs, from an ABI or a text signature
+#[derive(Debug, Clone)]
+struct FunctionSignature {
+    abi: String,
+    name: String,
+    inputs: Vec<(String, ethabi::ParamType)>,
+}
+
+impl FunctionSignature {
+    /// Parse a text signature like `transfer(address,uint256)`
+    fn parse(signature: &str) -> Option<FunctionSignature> {
+        let open = signature.find('(')?;
+        let name = signature[..open].trim().to_string();
+        let params = signature[open..].trim();
+        let kinds = if params == "()" {
+            vec![]
+        } else {
+            match ethabi::param_type::Reader::read(params).ok()? {
+                ethabi::ParamType::Tuple(kinds) => kinds,
+                _ => return None,
+            }
+        };
+        Some(FunctionSignature {
+            abi: SIGNATURE_DATABASE_NAME.to_string(),
+            name,
+            inputs: kinds.into_iter().map(|k| (String::new(), k)).collect(),
+        })
+    }
+
+    fn signature(&self) -> String {
+        let params: Vec<String> = self
+            .inputs
+            .iter()
+            .map(|(name, kind)| {
+                if name.is_empty() {
+                    kind.to_string()
+                } else {
+                    format!("{} {}", kind, name)
+                }
+            })
+            .collect();
+        format!("{}({})", self.name, params.join(", "))
+    }
+
+    fn selector(&self) -> [u8; 4] {
+        let kinds: Vec<ethabi::ParamType> = self.inputs.iter().map(|(_, k)| k.clone()).collect();
+        ethabi::short_signature(&self.name, &kinds)
+    }
+
+    /// Decode the arguments, only accepted when re-encoding gives back the same bytes
+    /// so a selector collision does not produce garbage
+    fn decode(&self, args: &[u8]) -> Option<Vec<DecodedParam>> {
+        let kinds: Vec<ethabi::ParamType> = self.inputs.iter().map(|(_, k)| k.clone()).collect();
+        let tokens = ethabi::decode(&kinds, args).ok()?;
+        if ethabi::encode(&tokens) != args {
+            return None;
+        }
+        Some(
+            self.inputs
+                .iter()
+                .zip(tokens)
+                .map(|((name, kind), token)| DecodedParam {
+                    name: name.clone(),
+                    kind: kind.to_string(),
+                    value: token_to_string(&token),
+                    indexed: false,
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DecodedParam {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+    pub indexed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DecodedEvent {
+    /// Name of the ABI the event was found in
+    pub abi: String,
+    pub name: String,
+    pub signature: String,
+    pub params: Vec<DecodedParam>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DecodedCall {
+    /// Name of the ABI the function was found in, or the signature database
+    pub abi: String,
+    pub name: String,
+    pub signature: String,
+    pub params: Vec<DecodedParam>,
+}
+
+/// Transaction calldata, decoded when the selector is known
+/// and always split in 32-byte words as a fallback
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DecodedInput {
+    pub selector: Option<String>,
+    pub call: Option<DecodedCall>,
+    pub words: Vec<String>,
+}
+
+/// A receipt log as shown on the transaction page
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DisplayLog {
+    pub address: String,
+    pub topics: Vec<String>,
+    pub data: String,
+    pub decoded: Option<DecodedEvent>,
+}
+
+impl AbiRegistry {
+    /// Registry with the built-in ERC-20/721/1155 ABIs
+    pub fn new() -> AbiRegistry {
+        let mut registry = AbiRegistry {
+            events: HashMap::new(),
+            functions: HashMap::new(),
+        };
+        for (name, abi) in BUILTIN_ABIS {
+            registry
+                .register(name, abi)
+                .expect("Built-in ABIs are valid");
+        }
+        for line in SIGNATURE_DATABASE.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match FunctionSignature::parse(line) {
+                Some(function) => registry.add_function(function),
+                None => println!("Invalid signature in database: {}", line),
+            }
+        }
+        registry
+    }
+
+    /// Register every event and function of a JSON ABI
+    pub fn register(&mut self, name: &str, abi: &str) -> Result<(), Error> {
+        let contract = ethabi::Contract::load(abi.as_bytes())
+            .map_err(|e| Error::Parse(format!("Invalid ABI {}: {}", name, e)))?;
+        for event in contract.events() {
+            self.events
+                .entry(event.signature())
+                .or_default()
+                .push((name.to_string(), event.clone()));
+        }
+        for function in contract.functions() {
+            self.add_function(FunctionSignature {
+                abi: name.to_string(),
+                name: function.name.clone(),
+                inputs: function
+                    .inputs
+                    .iter()
+                    .map(|i| (i.name.clone(), i.kind.clone()))
+                    .collect(),
+            });
+        }
+        Ok(())
+    }
+
+    /// ABI functions are tried before the signature database ones,
+    /// since they carry the parameter names
+    fn add_function(&mut self, function: FunctionSignature) {
+        let functions = self.functions.entry(function.selector()).or_default();
+        if function.abi == SIGNATURE_DATABASE_NAME {
+            functions.push(function);
+        } else {
+            let position = functions
+                .iter()
+                .position(|f| f.abi == SIGNATURE_DATABASE_NAME)
+                .unwrap_or(functions.len());
+            functions.insert(position, function);
+        }
+    }
+
+    /// Decode 0x prefixed calldata
+    pub fn decode_input(&self, input: &str) -> DecodedInput {
+        let bytes = decode_hex(input).unwrap_or_default();
+        if bytes.len() < 4 {
+            return DecodedInput {
+                selector: None,
+                call: None,
+                words: vec![],
+            };
+        }
+
+        let (selector, args) = bytes.split_at(4);
+        let call = self.functions.get(selector).and_then(|functions| {
+            functions.iter().find_map(|function| {
+                function.decode(args).map(|params| DecodedCall {
+                    abi: function.abi.clone(),
+                    name: function.name.clone(),
+                    signature: function.signature(),
+                    params,
+                })
+            })
+        });
+
+        DecodedInput {
+            selector: Some(format!("0x{}", hex::encode(selector))),
+            call,
+            words: args
+                .chunks(32)
+                .map(|w| format!("0x{}", hex::encode(w)))
+                .collect(),
+        }
+    }
+
+    /// Register every *.json ABI file in a directory, named after the file
+    pub fn load_dir(&mut self, dir: &str) -> Result<(), Error> {
+        let entries = std::fs::read_dir(dir)
+            .map_err(|e| Error::Parse(format!("Unable to read ABI dir {}: {}", dir, e)))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let abi = std::fs::read_to_string(&path)
+                .map_err(|e| Error::Parse(format!("Unable to read ABI {}: {}", name, e)))?;
+            self.register(&name, &abi)?;
+            println!("Registered ABI {}", name);
+        }
+        Ok(())
+    }
+
+    /// Decode a log against the known events, None when no ABI matches
+    pub fn decode_log(&self, topics: &[H256], data: &[u8]) -> Option<DecodedEvent> {
+        let candidates = self.events.get(topics.first()?)?;
+        candidates.iter().find_map(|(abi, event)| {
+            let log = event
+                .parse_log(ethabi::RawLog {
+                    topics: topics.to_vec(),
+                    data: data.to_vec(),
+                })
+                .ok()?;
+            let params = event
+                .inputs
+                .iter()
+                .zip(log.params)
+                .map(|(input, param)| DecodedParam {
+                    name: param.name,
+                    kind: input.kind.to_string(),
+                    value: token_to_string(&param.value),
+                    indexed: input.indexed,
+                })
+                .collect();
+            Some(DecodedEvent {
+                abi: abi.clone(),
+                name: event.name.clone(),
+                signature: event_signature(event),
+                params,
+            })
+        })
+    }
+
+    /// Add the decoded calldata to a transaction JSON object as `decodedInput`
+    pub fn annotate_transaction(&self, transaction: &mut serde_json::Value) -> Result<(), Error> {
+        if let Some(input) = transaction["input"].as_str() {
+            let decoded = serde_json::to_value(self.decode_input(input))?;
+            transaction["decodedInput"] = decoded;
+        }
+        Ok(())
+    }
+
+    /// Decode the logs of a (raw or human readable) receipt
+    pub fn decode_logs(&self, receipt: &serde_json::Value) -> Vec<DisplayLog> {
+        receipt["logs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|log| {
+                let topics: Vec<String> = log["topics"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|t| t.as_str().map(|t| t.to_string()))
+                    .collect();
+                let data = log["data"].as_str().unwrap_or("0x").to_string();
+                let parsed_topics: Option<Vec<H256>> =
+                    topics.iter().map(|t| t.parse().ok()).collect();
+                let decoded = match (parsed_topics, decode_hex(&data)) {
+                    (Some(parsed_topics), Some(bytes)) => self.decode_log(&parsed_topics, &bytes),
+                    _ => None,
+                };
+                DisplayLog {
+                    address: log["address"].as_str().unwrap_or_default().to_string(),
+                    topics,
+                    data,
+                    decoded,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Default for AbiRegistry {
+    fn default() -> Self {
+        AbiRegistry::new()
+    }
+}
+
+/// Human readable signature, e.g. `Transfer(address indexed from, address indexed to, uint256 value)`
+fn event_signature(event: &ethabi::Event) -> String {
+    let params: Vec<String> = event
+        .inputs
+        .iter()
+        .map(|i| {
+            let indexed = if i.indexed { " indexed" } else { "" };
+            format!("{}{} {}", i.kind, indexed, i.name)
+        })
+        .collect();
+    format!("{}({})", event.name, params.join(", "))
+}
+
+/// Decode a 0x prefixed hex string
+pub fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    hex::decode(s.trim_start_matches("0x")).ok()
+}
+
+/// Display a decoded value: decimal numbers, checksum-less 0x addresses and bytes
+pub fn token_to_string(token: &ethabi::Token) -> String {
+    match token {
+        ethabi::Token::Address(a) => format!("{:?}", a),
+        ethabi::Token::Uint(i) => i.to_string(),
+        ethabi::Token::Int(i) => {
+            // two's complement
+            if i.bit(255) {
+                format!("-{}", (!*i).overflowing_add(U256::one()).0)
+            } else {
+                i.to_string()
+            }
+        }
+        ethabi::Token::Bytes(b) | ethabi::Token::FixedBytes(b) => format!("0x{}", hex::encode(b)),
+        ethabi::Token::Bool(b) => b.to_string(),
+        ethabi::Token::String(s) => s.clone(),
+        ethabi::Token::Array(tokens) | ethabi::Token::FixedArray(tokens) => {
+            let values: Vec<String> = tokens.iter().map(token_to_string).collect();
+            format!("[{}]", values.join(", "))
+        }
+        ethabi::Token::Tuple(tokens) => {
+            let values: Vec<String> = tokens.iter().map(token_to_string).collect();
+            format!("({})", values.join(", "))
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use crate::cache::{BackendStats, CacheBackend, CacheOp};
+use crate::error::Error;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Embedded on-disk backend, everything lives in one sled tree
+/// so a batch is applied atomically. Keys are namespaced by their first byte:
+/// - `v<key>`: expiry (8 bytes, unix seconds, 0 for never), write time (8 bytes, unix seconds)
+///   followed by the value
+/// - `h<key>`: field map as JSON
+/// - `z<key>\0<score><member>`: scored set entry, ordered by score
+/// - `m<key>\0<member>`: score of a scored set member
+/// - `c<key>`: marks the value at `v<key>` as a cached node response
+///
+/// Expired values are dropped when read and by `maintain`, which also evicts
+/// the least recently written cached values while they are over the budget.
+/// Values stored with `set` and `batch` (address index, crawler state, user data)
+/// are never evicted.
+pub struct DiskCache {
+    db: sled::Db,
+    /// 0 for no limit
+    max_bytes: u64,
+    evicted: AtomicU64,
+}
+
+const VALUE: u8 = b'v';
+const FIELDS: u8 = b'h';
+const SCORED: u8 = b'z';
+const MEMBER: u8 = b'm';
+const CACHED: u8 = b'c';
+
+/// Expiry and write time in front of every value
+const VALUE_HEADER: usize = 16;
+
+fn namespaced(namespace: u8, key: &str) -> Vec<u8> {
+    let mut k = vec![namespace];
+    k.extend_from_slice(key.as_bytes());
+    k
+}
+
+/// Prefix shared by every entry of the scored set at key
+fn scored_prefix(namespace: u8, key: &str) -> Vec<u8> {
+    let mut k = namespaced(namespace, key);
+    k.push(0);
+    k
+}
+
+/// Big endian bytes of the score that sort like the score itself
+fn score_bytes(score: f64) -> [u8; 8] {
+    let bits = score.to_bits();
+    let ordered = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    };
+    ordered.to_be_bytes()
+}
+
+/// Key of a scored set entry, ordered by score then member
+fn scored_entry_key(key: &str, score: f64, member: &str) -> Vec<u8> {
+    let mut k = scored_prefix(SCORED, key);
+    k.extend_from_slice(&score_bytes(score));
+    k.extend_from_slice(member.as_bytes());
+    k
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Stored form of a value: expiry, write time and the value itself
+fn value_bytes(value: &str, ttl: Option<Duration>) -> Vec<u8> {
+    let expiry = ttl.map_or(0, |ttl| now() + ttl.as_secs().max(1));
+    let mut raw = expiry.to_be_bytes().to_vec();
+    raw.extend_from_slice(&now().to_be_bytes());
+    raw.extend_from_slice(value.as_bytes());
+    raw
+}
+
+/// Read the big endian u64 at the start of bytes
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(raw)
+}
+
+impl DiskCache {
+    pub fn open(path: &str, max_bytes: u64) -> Result<DiskCache, Error> {
+        Ok(DiskCache {
+            db: sled::open(path)?,
+            max_bytes,
+            evicted: AtomicU64::new(0),
+        })
+    }
+
+    /// Expiry and value stored at key, None when missing or expired
+    fn value(&self, key: &str) -> Result<Option<(u64, String)>, Error> {
+        let raw = match self.db.get(namespaced(VALUE, key))? {
+            Some(raw) if raw.len() >= VALUE_HEADER => raw,
+            _ => return Ok(None),
+        };
+        let expiry = be_u64(&raw);
+        if expiry != 0 && expiry <= now() {
+            self.db.remove(namespaced(VALUE, key))?;
+            self.db.remove(namespaced(CACHED, key))?;
+            return Ok(None);
+        }
+        let value = String::from_utf8_lossy(&raw[VALUE_HEADER..]).to_string();
+        Ok(Some((expiry, value)))
+    }
+
+    /// (write time, size, key) of every cached value
+    fn cached(&self) -> Result<Vec<(u64, u64, String)>, Error> {
+        let mut values = vec![];
+        for entry in self.db.scan_prefix([CACHED]) {
+            let (marker, _) = entry?;
+            let key = String::from_utf8_lossy(&marker[1..]).to_string();
+            if let Some(raw) = self.db.get(namespaced(VALUE, &key))? {
+                if raw.len() >= VALUE_HEADER {
+                    let size = (marker.len() + key.len() + 1 + raw.len()) as u64;
+                    values.push((be_u64(&raw[8..]), size, key));
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// Amount of keys and bytes stored. Scored sets count as one key.
+    fn usage(&self) -> Result<(u64, u64), Error> {
+        let (mut keys, mut bytes) = (0, 0);
+        let mut last_set: Option<Vec<u8>> = None;
+        for entry in self.db.iter() {
+            let (k, v) = entry?;
+            bytes += (k.len() + v.len()) as u64;
+            match k[0] {
+                VALUE | FIELDS => keys += 1,
+                MEMBER => {
+                    let set = k.split(|b| *b == 0).next().unwrap_or_default().to_vec();
+                    if last_set.as_ref() != Some(&set) {
+                        keys += 1;
+                        last_set = Some(set);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok((keys, bytes))
+    }
+
+    /// Queue the removal of the current entry of a scored set member, if any.
+    /// `scores` holds the scores already changed in the batch being built.
+    /// Returns the key of the member score.
+    fn remove_scored(
+        &self,
+        batch: &mut sled::Batch,
+        scores: &HashMap<(String, String), Option<f64>>,
+        key: &str,
+        member: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let mut member_key = scored_prefix(MEMBER, key);
+        member_key.extend_from_slice(member.as_bytes());
+        let old = match scores.get(&(key.to_string(), member.to_string())) {
+            Some(old) => *old,
+            None => self.db.get(&member_key)?.map(|raw| {
+                let mut bits = [0u8; 8];
+                bits.copy_from_slice(&raw[..8]);
+                f64::from_be_bytes(bits)
+            }),
+        };
+        if let Some(old) = old {
+            batch.remove(scored_entry_key(key, old, member));
+        }
+        Ok(member_key)
+    }
+
+    /// Entries of the scored set at key between min and max, in ascending order
+    fn scored(&self, key: &str, min: f64, max: f64) -> sled::Iter {
+        let prefix = scored_prefix(SCORED, key);
+        let mut start = prefix.clone();
+        start.extend_from_slice(&score_bytes(min));
+        let mut end = prefix;
+        end.extend_from_slice(&score_bytes(max));
+        // Members are UTF-8, which never contains 0xff
+        end.push(0xff);
+        self.db
+            .range::<Vec<u8>, _>((Bound::Included(start), Bound::Excluded(end)))
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheBackend for DiskCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(self.value(key)?.map(|(_, v)| v))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error> {
+        self.batch(vec![CacheOp::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+            ttl,
+        }])
+        .await
+    }
+
+    async fn cache(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        batch.insert(namespaced(VALUE, key), value_bytes(value, ttl));
+        batch.insert(namespaced(CACHED, key), vec![]);
+        Ok(self.db.apply_batch(batch)?)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.value(key)?.is_some()
+            || self.db.contains_key(namespaced(FIELDS, key))?
+            || self
+                .db
+                .scan_prefix(scored_prefix(MEMBER, key))
+                .next()
+                .is_some())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.batch(vec![CacheOp::Delete {
+            key: key.to_string(),
+        }])
+        .await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, Error> {
+        Ok(self.value(key)?.and_then(|(expiry, _)| {
+            (expiry != 0).then(|| Duration::from_secs(expiry.saturating_sub(now())))
+        }))
+    }
+
+    async fn batch(&self, ops: Vec<CacheOp>) -> Result<(), Error> {
+        let mut batch = sled::Batch::default();
+        // Field maps and scores changed earlier in this batch, sled batches can not be read
+        let mut fields: HashMap<String, HashMap<String, String>> = HashMap::new();
+        let mut scores: HashMap<(String, String), Option<f64>> = HashMap::new();
+
+        for op in ops {
+            match op {
+                CacheOp::Set { key, value, ttl } => {
+                    batch.insert(namespaced(VALUE, &key), value_bytes(&value, ttl));
+                    batch.remove(namespaced(CACHED, &key));
+                }
+                CacheOp::Delete { key } => {
+                    batch.remove(namespaced(VALUE, &key));
+                    batch.remove(namespaced(CACHED, &key));
+                    batch.remove(namespaced(FIELDS, &key));
+                    fields.insert(key.clone(), HashMap::new());
+                    for entry in self
+                        .db
+                        .scan_prefix(scored_prefix(SCORED, &key))
+                        .chain(self.db.scan_prefix(scored_prefix(MEMBER, &key)))
+                    {
+                        batch.remove(entry?.0);
+                    }
+                    scores.retain(|(k, _), _| *k != key);
+                }
+                CacheOp::SetFields { key, fields: new } => {
+                    let map = match fields.remove(&key) {
+                        Some(map) => map,
+                        None => match self.db.get(namespaced(FIELDS, &key))? {
+                            Some(raw) => serde_json::from_slice(&raw)?,
+                            None => HashMap::new(),
+                        },
+                    };
+                    let mut map = map;
+                    map.extend(new);
+                    batch.insert(namespaced(FIELDS, &key), serde_json::to_vec(&map)?);
+                    fields.insert(key, map);
+                }
+                CacheOp::AddScored { key, member, score } => {
+                    let member_key = self.remove_scored(&mut batch, &scores, &key, &member)?;
+                    batch.insert(scored_entry_key(&key, score, &member), vec![]);
+                    batch.insert(member_key, score.to_be_bytes().to_vec());
+                    scores.insert((key, member), Some(score));
+                }
+                CacheOp::RemoveScored { key, member } => {
+                    let member_key = self.remove_scored(&mut batch, &scores, &key, &member)?;
+                    batch.remove(member_key);
+                    scores.insert((key, member), None);
+                }
+            }
+        }
+
+        Ok(self.db.apply_batch(batch)?)
+    }
+
+    async fn fields(&self, keys: &[String]) -> Result<Vec<HashMap<String, String>>, Error> {
+        let mut maps = vec![];
+        for key in keys {
+            maps.push(match self.db.get(namespaced(FIELDS, key))? {
+                Some(raw) => serde_json::from_slice(&raw)?,
+                None => HashMap::new(),
+            });
+        }
+        Ok(maps)
+    }
+
+    async fn range_by_score(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+        ascending: bool,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        let skip = scored_prefix(SCORED, key).len() + 8;
+        let member = |entry: sled::Result<(sled::IVec, sled::IVec)>| {
+            entry.map(|(k, _)| String::from_utf8_lossy(&k[skip..]).to_string())
+        };
+        let entries = self.scored(key, min, max);
+        let members: sled::Result<Vec<String>> = if ascending {
+            entries.skip(offset).take(count).map(member).collect()
+        } else {
+            entries.rev().skip(offset).take(count).map(member).collect()
+        };
+        Ok(members?)
+    }
+
+    async fn count_by_score(&self, key: &str, min: f64, max: f64) -> Result<usize, Error> {
+        Ok(self.scored(key, min, max).count())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = BTreeSet::new();
+        for namespace in [VALUE, FIELDS, MEMBER] {
+            for entry in self.db.scan_prefix(namespaced(namespace, prefix)) {
+                let raw = entry?.0;
+                let key = String::from_utf8_lossy(&raw[1..]).to_string();
+                // Scored sets are stored as `<key>\0<member>`
+                let key = key.split('\0').next().unwrap_or_default().to_string();
+                if namespace != VALUE || self.value(&key)?.is_some() {
+                    keys.insert(key);
+                }
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    async fn stats(&self) -> Result<BackendStats, Error> {
+        let (keys, bytes) = self.usage()?;
+        let cached_bytes = self.cached()?.iter().map(|(_, size, _)| *size).sum();
+        Ok(BackendStats {
+            keys,
+            bytes,
+            cached_bytes,
+            evicted: self.evicted.load(Ordering::Relaxed),
+        })
+    }
+
+    async fn maintain(&self) -> Result<(), Error> {
+        let now = now();
+        for entry in self.db.scan_prefix([VALUE]) {
+            let (k, v) = entry?;
+            let expiry = if v.len() < VALUE_HEADER {
+                0
+            } else {
+                be_u64(&v)
+            };
+            if expiry != 0 && expiry <= now {
+                self.db.remove(&k)?;
+                self.db
+                    .remove(namespaced(CACHED, &String::from_utf8_lossy(&k[1..])))?;
+            }
+        }
+
+        if self.max_bytes == 0 {
+            return Ok(());
+        }
+        let mut values = self.cached()?;
+        let mut bytes: u64 = values.iter().map(|(_, size, _)| *size).sum();
+        values.sort_by_key(|(written, _, _)| *written);
+        for (_, size, key) in values {
+            if bytes <= self.max_bytes {
+                break;
+            }
+            let mut batch = sled::Batch::default();
+            batch.remove(namespaced(VALUE, &key));
+            batch.remove(namespaced(CACHED, &key));
+            self.db.apply_batch(batch)?;
+            bytes = bytes.saturating_sub(size);
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DiskCache;
+    use crate::cache::{CacheBackend, CacheOp};
+    use std::time::Duration;
+
+    /// A store in a fresh directory, removed when the test ends
+    struct TestStore {
+        cache: DiskCache,
+        path: std::path::PathBuf,
+    }
+
+    impl TestStore {
+        fn open(name: &str, max_bytes: u64) -> TestStore {
+            let path = std::env::temp_dir().join(format!(
+                "etherglass-test-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            TestStore {
+                cache: DiskCache::open(path.to_str().unwrap(), max_bytes).unwrap(),
+                path,
+            }
+        }
+    }
+
+    impl Drop for TestStore {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn add(key: &str, member: &str, score: f64) -> CacheOp {
+        CacheOp::AddScored {
+            key: key.to_string(),
+            member: member.to_string(),
+            score,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn values_expire() {
+        let store = TestStore::open("expire", 0);
+        let cache = &store.cache;
+        cache.set("kept", "1", None).await.unwrap();
+        cache
+            .set("short", "2", Some(Duration::from_secs(1)))
+            .await
+            .unwrap();
+        assert_eq!(cache.get("kept").await.unwrap().as_deref(), Some("1"));
+        assert_eq!(cache.get("short").await.unwrap().as_deref(), Some("2"));
+        assert_eq!(cache.ttl("kept").await.unwrap(), None);
+        assert!(cache.ttl("short").await.unwrap().is_some());
+
+        // Expiry is kept in seconds
+        rocket::tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(cache.get("short").await.unwrap(), None);
+        assert!(!cache.exists("short").await.unwrap());
+        cache.delete("kept").await.unwrap();
+        assert_eq!(cache.get("kept").await.unwrap(), None);
+    }
+
+    #[rocket::async_test]
+    async fn batch_and_scored_sets() {
+        let store = TestStore::open("batch", 0);
+        let cache = &store.cache;
+        cache
+            .batch(vec![
+                CacheOp::Set {
+                    key: "value".to_string(),
+                    value: "v".to_string(),
+                    ttl: None,
+                },
+                CacheOp::SetFields {
+                    key: "map".to_string(),
+                    fields: vec![("a".to_string(), "1".to_string())],
+                },
+                add("set", "c", 3.0),
+                add("set", "a", 1.0),
+                add("set", "b", 2.0),
+                add("set", "d", 4.0),
+                CacheOp::RemoveScored {
+                    key: "set".to_string(),
+                    member: "d".to_string(),
+                },
+                // A member added again moves to its new score
+                add("set", "a", 5.0),
+                CacheOp::Delete {
+                    key: "value".to_string(),
+                },
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(cache.get("value").await.unwrap(), None);
+        let keys = ["map".to_string(), "none".to_string()];
+        let fields = cache.fields(&keys).await.unwrap();
+        assert_eq!(fields[0].get("a").map(String::as_str), Some("1"));
+        assert!(fields[1].is_empty());
+
+        let range = |ascending, offset, count| {
+            cache.range_by_score("set", 2.0, 5.0, ascending, offset, count)
+        };
+        assert_eq!(range(true, 0, 10).await.unwrap(), ["b", "c", "a"]);
+        assert_eq!(range(false, 0, 10).await.unwrap(), ["a", "c", "b"]);
+        assert_eq!(range(true, 1, 1).await.unwrap(), ["c"]);
+        assert_eq!(cache.count_by_score("set", 2.0, 3.0).await.unwrap(), 2);
+        assert_eq!(cache.count_by_score("none", 0.0, 9.0).await.unwrap(), 0);
+    }
+
+    #[rocket::async_test]
+    async fn evicts_least_recently_written_responses_only() {
+        // Each response takes 30 bytes with its marker and header
+        let store = TestStore::open("evict", 70);
+        let cache = &store.cache;
+        cache.set("kept", &"x".repeat(100), None).await.unwrap();
+        cache.cache("r1", "12345678", None).await.unwrap();
+        // Write times are kept in seconds
+        rocket::tokio::time::sleep(Duration::from_millis(1100)).await;
+        cache.cache("r2", "12345678", None).await.unwrap();
+        cache.cache("r3", "12345678", None).await.unwrap();
+        // Reading does not matter, only writing
+        cache.get("r1").await.unwrap();
+        cache.maintain().await.unwrap();
+
+        assert!(cache.exists("kept").await.unwrap());
+        assert!(!cache.exists("r1").await.unwrap());
+        assert!(cache.exists("r2").await.unwrap());
+        assert!(cache.exists("r3").await.unwrap());
+        let stats = cache.stats().await.unwrap();
+        assert_eq!(stats.cached_bytes, 60);
+        assert_eq!(stats.evicted, 1);
+    }
+}
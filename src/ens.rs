@@ -0,0 +1,184 @@
+use crate::error::Error;
+use crate::EtherClient;
+use futures::stream::{self, StreamExt};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use web3::contract::Error as ContractError;
+use web3::types::H160;
+
+/// ENS details of a name shown on the address page
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EnsProfile {
+    pub name: String,
+    pub owner: Option<String>,
+    pub resolver: Option<String>,
+    pub avatar: Option<String>,
+    pub url: Option<String>,
+    pub twitter: Option<String>,
+}
+
+/// Reverse lookups sent to the node at the same time
+const LOOKUP_CONCURRENCY: usize = 16;
+
+/// Most addresses of a page resolved to names, the others are shown without one
+const MAX_LOOKUPS: usize = 500;
+
+/// Primary name of an address, "" when it has none
+fn name_key(address: &str) -> String {
+    format!("ensname_{}", address)
+}
+
+/// Address a name resolves to, "" when it has none
+fn address_key(name: &str) -> String {
+    format!("ensaddress_{}", name)
+}
+
+fn profile_key(name: &str) -> String {
+    format!("ensprofile_{}", name)
+}
+
+/// The value of a lookup, None when the records are missing.
+/// Calls to an unset resolver return no data and fail to decode, that means no record,
+/// only failures of the node itself are errors.
+fn found<T>(result: Result<T, ContractError>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ContractError::Api(e)) => Err(Error::Rpc(e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn non_zero(address: H160) -> Option<String> {
+    (!address.is_zero()).then(|| format!("{:?}", address))
+}
+
+/// Primary name of an address from its `addr.reverse` record.
+/// The name only counts when it resolves back to the same address.
+pub async fn primary_name(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    address: H160,
+) -> Result<Option<String>, Error> {
+    let key = name_key(&format!("{:?}", address));
+    if let Some(name) = cache.get_ens(&key).await? {
+        return Ok(Some(name).filter(|n| !n.is_empty()));
+    }
+
+    let name = match found(client.ens.canonical_name(address).await)? {
+        Some(name) if !name.is_empty() => {
+            let forward = found(client.ens.eth_address(&name).await)?;
+            Some(name).filter(|_| forward == Some(address))
+        }
+        _ => None,
+    };
+
+    cache
+        .set_ens(&key, name.as_deref().unwrap_or_default())
+        .await?;
+    Ok(name)
+}
+
+/// Address of a name, None when it does not resolve
+pub async fn resolve(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    name: &str,
+) -> Result<Option<H160>, Error> {
+    let name = name.trim().to_lowercase();
+    let key = address_key(&name);
+    if let Some(address) = cache.get_ens(&key).await? {
+        return Ok(address.parse().ok());
+    }
+
+    let address = found(client.ens.eth_address(&name).await)?.filter(|a| !a.is_zero());
+    let cached = address.map(|a| format!("{:?}", a)).unwrap_or_default();
+    cache.set_ens(&key, &cached).await?;
+    Ok(address)
+}
+
+/// Addresses found anywhere in a page context, lowercase
+pub fn addresses_in(value: &serde_json::Value) -> BTreeSet<String> {
+    let mut found = BTreeSet::new();
+    collect_addresses(value, &mut found);
+    found
+}
+
+fn collect_addresses(value: &serde_json::Value, found: &mut BTreeSet<String>) {
+    match value {
+        serde_json::Value::String(s) if s.len() == 42 && s.starts_with("0x") => {
+            if let Ok(address) = s.parse::<H160>() {
+                found.insert(format!("{:?}", address));
+            }
+        }
+        serde_json::Value::Array(values) => {
+            values.iter().for_each(|v| collect_addresses(v, found));
+        }
+        serde_json::Value::Object(map) => {
+            map.values().for_each(|v| collect_addresses(v, found));
+        }
+        _ => {}
+    }
+}
+
+/// Primary names of the addresses of a page, by lowercase address.
+/// Pages still render without names when the node fails.
+pub async fn names_for_page(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    addresses: BTreeSet<String>,
+) -> HashMap<String, String> {
+    stream::iter(addresses.into_iter().take(MAX_LOOKUPS))
+        .map(|address| async move {
+            let parsed = address.parse::<H160>().ok()?;
+            match primary_name(client, cache, parsed).await {
+                Ok(name) => name.map(|name| (address, name)),
+                Err(e) => {
+                    println!("ENS name of {} unavailable: {}", address, e);
+                    None
+                }
+            }
+        })
+        .buffer_unordered(LOOKUP_CONCURRENCY)
+        .filter_map(|found| async move { found })
+        .collect()
+        .await
+}
+
+/// Owner, resolver and text records of a name
+pub async fn profile(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    name: &str,
+) -> Result<EnsProfile, Error> {
+    let key = profile_key(name);
+    if let Some(profile) = cache.get_ens(&key).await? {
+        return Ok(serde_json::from_str(&profile)?);
+    }
+
+    let ens = &client.ens;
+    let text = |key: &'static str| async move {
+        found(ens.text(name, key.to_string()).await)
+            .map(|value| value.filter(|value| !value.is_empty()))
+    };
+    let (owner, resolver, avatar, url, twitter) = futures::join!(
+        ens.owner(name),
+        ens.resolver(name),
+        text("avatar"),
+        text("url"),
+        text("com.twitter"),
+    );
+    let profile = EnsProfile {
+        name: name.to_string(),
+        owner: found(owner)?.and_then(non_zero),
+        resolver: found(resolver)?.and_then(non_zero),
+        avatar: avatar?,
+        url: url?,
+        twitter: twitter?,
+    };
+
+    cache
+        .set_ens(&key, &serde_json::to_string(&profile)?)
+        .await?;
+    Ok(profile)
+}
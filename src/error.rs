@@ -0,0 +1,109 @@
+use rocket::http::Status;
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::Json;
+use rocket::Request;
+use rocket_dyn_templates::{context, Template};
+
+/// Crate-wide error type.
+/// Page handlers return it directly and get a rendered error page,
+/// the JSON api wraps it in `ApiError` to get a JSON error body.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The node could not be reached or returned an error
+    #[error("RPC error: {0}")]
+    Rpc(#[from] web3::Error),
+    /// The cache backend is unreachable or a command failed
+    #[error("Cache error: {0}")]
+    Cache(String),
+    /// The input or a value returned by the node or the cache is malformed
+    #[error("Parse error: {0}")]
+    Parse(String),
+    /// The block, transaction, address or name does not exist
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// The request needs a valid session or the login was refused
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    /// The request is reserved to the admins
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    /// The action does not apply in the current state, e.g. pausing a crawl that is not running
+    #[error("Conflict: {0}")]
+    Conflict(String),
+}
+
+impl Error {
+    pub fn status(&self) -> Status {
+        match self {
+            Error::Rpc(_) => Status::BadGateway,
+            Error::Cache(_) => Status::ServiceUnavailable,
+            Error::Parse(_) => Status::BadRequest,
+            Error::NotFound(_) => Status::NotFound,
+            Error::Unauthorized(_) => Status::Unauthorized,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::Conflict(_) => Status::Conflict,
+        }
+    }
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(e: redis::RedisError) -> Self {
+        Error::Cache(format!("{}", e))
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Cache(format!("{}", e))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Parse(format!("{}", e))
+    }
+}
+
+/// Renders the error template with the matching status code
+impl<'r> Responder<'r, 'static> for Error {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        println!("Request failed: {}", self);
+        let template = Template::render(
+            "error",
+            context! {
+                code: status.code,
+                reason: status.reason(),
+                message: self.to_string(),
+            },
+        );
+        Response::build_from(template.respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
+
+/// Error returned by the JSON api, rendered as `{"error": {"code", "message"}}`
+#[derive(Debug)]
+pub struct ApiError(pub Error);
+
+impl From<Error> for ApiError {
+    fn from(e: Error) -> Self {
+        ApiError(e)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.0.status();
+        let body = Json(serde_json::json!({
+            "error": {
+                "code": status.code,
+                "message": self.0.to_string(),
+            }
+        }));
+        Response::build_from(body.respond_to(req)?)
+            .status(status)
+            .ok()
+    }
+}
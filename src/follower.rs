@@ -0,0 +1,261 @@
+use crate::error::Error;
+use crate::node::{self, TransportKind};
+use crate::{pages, parser, Cache, EtherClient, RequestData};
+use rocket::fairing::AdHoc;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::broadcast::Sender;
+use std::collections::BTreeSet;
+use std::time::Duration;
+use web3::futures::StreamExt;
+
+/// A new block, sent to the `/latest_blocks` SSE clients.
+/// After a reorg the blocks replacing the old ones are sent again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct BlockEvent {
+    pub block: pages::home::SimpleBlock,
+}
+
+/// The transactions of a new block, sent to the `/watch` SSE clients.
+/// Only fetched while somebody is watching.
+#[derive(Debug, Clone)]
+pub struct BlockActivity {
+    pub number: u64,
+    pub hash: String,
+    /// Head at the time the block was published
+    pub head: u64,
+    pub transactions: Vec<pages::block::SimpleTransaction>,
+}
+
+/// Blocks published and indexed at most for one head when the follower fell behind,
+/// older ones are recorded as crawler gaps
+const MAX_CATCH_UP: u64 = 100;
+
+/// Wait before subscribing again after losing a subscription
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Follow the head in the background from launch, whether or not a browser is connected.
+/// New heads are pushed by the WebSocket and IPC endpoints through `eth_subscribe`,
+/// with only HTTP endpoints the head is polled every `interval`.
+/// Every new block is published to the SSE clients, with its transactions while
+/// somebody watches an address or a transaction, and, with the cache, checked for reorgs and indexed.
+pub fn fairing(interval: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Head follower", move |rocket| {
+        Box::pin(async move {
+            let state = (
+                rocket.state::<Sender<BlockEvent>>(),
+                rocket.state::<Sender<BlockActivity>>(),
+                rocket.state::<EtherClient>(),
+                rocket.state::<Cache>(),
+            );
+            let (queues, client, cache) = match state {
+                (Some(queue), Some(activity), Some(client), Some(cache)) => (
+                    Queues {
+                        blocks: queue.clone(),
+                        activity: activity.clone(),
+                    },
+                    client.clone(),
+                    cache.clone(),
+                ),
+                _ => {
+                    println!("Head follower not started, the server state is missing");
+                    return;
+                }
+            };
+            rocket::tokio::spawn(follow(queues, client, cache, interval));
+        })
+    })
+}
+
+/// Where new blocks are published
+pub struct Queues {
+    pub blocks: Sender<BlockEvent>,
+    pub activity: Sender<BlockActivity>,
+}
+
+async fn follow(queues: Queues, client: EtherClient, cache: Cache, interval: Duration) {
+    let mut last_head = None;
+    let push_endpoints: Vec<String> = client
+        .web3
+        .transport()
+        .urls()
+        .into_iter()
+        .filter(|url| node::transport_kind(url) != TransportKind::Http)
+        .collect();
+
+    if push_endpoints.is_empty() {
+        println!("Polling new heads every {} seconds", interval.as_secs());
+        loop {
+            match poll_head(&queues, &client, &cache, last_head).await {
+                Ok(head) => last_head = Some(head),
+                Err(e) => println!("Polling failed: {}", e),
+            }
+            rocket::tokio::time::sleep(interval).await;
+        }
+    }
+
+    // Move on to the next endpoint whenever a subscription is lost
+    for endpoint in push_endpoints.iter().cycle() {
+        if let Err(e) = subscribe(endpoint, &queues, &client, &cache, &mut last_head).await {
+            println!(
+                "New heads subscription to {} lost: {}",
+                node::display_endpoint(endpoint),
+                node::redact(&e.to_string())
+            );
+        }
+        rocket::tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Follow the heads pushed by one endpoint until the subscription ends.
+/// Heads missed since `last_head` are caught up first.
+async fn subscribe(
+    endpoint: &str,
+    queues: &Queues,
+    client: &EtherClient,
+    cache: &Cache,
+    last_head: &mut Option<u64>,
+) -> Result<(), Error> {
+    let mut heads = node::subscribe_new_heads(endpoint).await?;
+    println!(
+        "Subscribed to new heads on {}",
+        node::display_endpoint(endpoint)
+    );
+
+    if last_head.is_some() {
+        match poll_head(queues, client, cache, *last_head).await {
+            Ok(head) => *last_head = Some(head),
+            // The next pushed head catches up instead
+            Err(e) => println!("Catching up missed heads failed: {}", e),
+        }
+    }
+
+    while let Some(header) = heads.next().await {
+        let head = match header?.number {
+            Some(number) => number.as_u64(),
+            None => continue,
+        };
+        match publish_new_blocks(queues, client, cache, *last_head, head, true).await {
+            Ok(head) => *last_head = Some(head),
+            Err(e) => println!("Following head {} failed: {}", head, e),
+        }
+    }
+    Err(Error::Rpc(web3::Error::Transport(
+        web3::error::TransportError::Message("The subscription ended".to_string()),
+    )))
+}
+
+/// Ask the node for its head and publish the blocks that appeared since `last_head`.
+/// Returns the new head.
+pub async fn poll_head(
+    queues: &Queues,
+    client: &EtherClient,
+    cache: &Cache,
+    last_head: Option<u64>,
+) -> Result<u64, Error> {
+    let block_number = &parser::parse_request(
+        client,
+        cache,
+        "eth",
+        "blockNumber",
+        RequestData {
+            data: serde_json::json!({}),
+        },
+    )
+    .await?
+    .data["blockNumber"];
+    let head = crate::json_value_hex_to_int(block_number.clone())? as u64;
+    publish_new_blocks(queues, client, cache, last_head, head, false).await
+}
+
+/// Fetch the transactions of a block for the watchers
+async fn block_activity(
+    client: &EtherClient,
+    cache: &Cache,
+    block_number: u64,
+    head: u64,
+) -> Result<BlockActivity, Error> {
+    let block = parser::parse_request(
+        client,
+        cache,
+        "eth",
+        "block",
+        RequestData {
+            data: serde_json::json!({ "blockNumber": block_number }),
+        },
+    )
+    .await?
+    .data["block"]
+        .clone();
+    let hash = crate::clean(block["hash"].to_string());
+    let transactions = pages::block::retrieve_transactions(
+        client,
+        block["transactions"].clone(),
+        cache,
+        hash.clone(),
+    )
+    .await?;
+    Ok(BlockActivity {
+        number: block_number,
+        hash,
+        head,
+        transactions,
+    })
+}
+
+/// Publish every block after `last_head` up to `head` to the SSE clients, and with the cache
+/// roll back the blocks replaced by a reorg and index the new ones.
+/// A `pushed` head is published even when it is not higher than the last one,
+/// nodes only push a known height again after a reorg.
+/// Returns the new head.
+async fn publish_new_blocks(
+    queues: &Queues,
+    client: &EtherClient,
+    cache: &Cache,
+    last_head: Option<u64>,
+    head: u64,
+    pushed: bool,
+) -> Result<u64, Error> {
+    let mut blocks = BTreeSet::new();
+    match last_head {
+        Some(last) if last < head => {
+            let first = (last + 1).max(head.saturating_sub(MAX_CATCH_UP));
+            if first > last + 1 && cache.enabled {
+                println!("Blocks {} to {} left to the crawler", last + 1, first - 1);
+                crate::crawler::add_gaps(cache, last + 1..=first - 1).await?;
+            }
+            blocks.extend(first..=head)
+        }
+        Some(_) if !pushed => {}
+        _ => {
+            blocks.insert(head);
+        }
+    }
+
+    if cache.enabled {
+        // Undo what was cached for blocks replaced by a reorg, they are indexed again below
+        blocks.extend(crate::reorg::track_head(client, cache, head).await?);
+    } else {
+        cache.observe_head(head);
+    }
+
+    for block_number in blocks {
+        let block = pages::home::simple_block(client, cache, block_number as i64).await?;
+        // Nobody listening is not an error
+        let _ = queues.blocks.send(BlockEvent { block });
+        if queues.activity.receiver_count() > 0 {
+            let activity = block_activity(client, cache, block_number, head).await?;
+            let _ = queues.activity.send(activity);
+        }
+        if cache.enabled {
+            pages::address::cache_addresses_transactions_from_block(
+                client,
+                cache,
+                block_number as i64,
+            )
+            .await?;
+        }
+    }
+
+    Ok(head.max(last_head.unwrap_or(0)))
+}
@@ -0,0 +1,340 @@
+use crate::error::Error;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use web3::types::H160;
+
+/// A public name for an address, such as an exchange, a bridge or a builder
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PublicLabel {
+    /// Lowercase 0x address
+    pub address: String,
+    pub name: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    /// Ticker of a token contract
+    #[serde(default)]
+    pub symbol: Option<String>,
+}
+
+/// What the registry currently serves
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RegistryStatus {
+    pub path: Option<String>,
+    pub labels: usize,
+    /// Unix time of the last successful load
+    pub loaded_at: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Loaded {
+    labels: HashMap<String, PublicLabel>,
+    modified: Option<SystemTime>,
+    loaded_at: Option<i64>,
+    last_error: Option<String>,
+}
+
+/// Server-wide address labels loaded from a JSON or CSV file.
+/// The file is loaded again when it changes, a file that fails to load
+/// keeps the labels of the previous one.
+#[derive(Clone, Default)]
+pub struct LabelRegistry {
+    path: Option<PathBuf>,
+    loaded: Arc<RwLock<Loaded>>,
+}
+
+/// Most results returned by a label search
+pub const MAX_SEARCH_RESULTS: usize = 20;
+
+impl LabelRegistry {
+    pub fn new(path: Option<&str>) -> LabelRegistry {
+        let registry = LabelRegistry {
+            path: path.map(PathBuf::from),
+            loaded: Arc::new(RwLock::new(Loaded::default())),
+        };
+        if let Err(e) = registry.reload() {
+            println!("{}", e);
+        }
+        registry
+    }
+
+    /// Load the file again, returns the amount of labels
+    pub fn reload(&self) -> Result<usize, Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let result = parse_file(path);
+        let mut loaded = self.loaded.write().unwrap();
+        // A broken file is not read again until it changes
+        loaded.modified = modified;
+        match result {
+            Ok(labels) => {
+                let count = labels.len();
+                loaded.labels = labels;
+                loaded.loaded_at = Some(now());
+                loaded.last_error = None;
+                println!("Loaded {} labels from {}", count, path.display());
+                Ok(count)
+            }
+            Err(e) => {
+                loaded.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+
+    /// Reload when the file changed since the last load
+    pub fn reload_if_changed(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != self.loaded.read().unwrap().modified {
+            if let Err(e) = self.reload() {
+                println!("{}", e);
+            }
+        }
+    }
+
+    pub fn get(&self, address: &str) -> Option<PublicLabel> {
+        self.loaded
+            .read()
+            .unwrap()
+            .labels
+            .get(&address.to_lowercase())
+            .cloned()
+    }
+
+    /// Labels whose name, category or token symbol contains the query, case insensitive,
+    /// names starting with it first
+    pub fn search(&self, query: &str, limit: usize) -> Vec<PublicLabel> {
+        let query = query.trim().to_lowercase();
+        if query.is_empty() {
+            return vec![];
+        }
+        let loaded = self.loaded.read().unwrap();
+        let mut found: Vec<(bool, &PublicLabel)> = loaded
+            .labels
+            .values()
+            .filter_map(|label| {
+                let name = label.name.to_lowercase();
+                let category = label.category.as_deref().unwrap_or_default().to_lowercase();
+                let symbol = label.symbol.as_deref().unwrap_or_default().to_lowercase();
+                if name.contains(&query) || category.contains(&query) || symbol.contains(&query) {
+                    Some((!name.starts_with(&query), label))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        found.sort_by(|(a_later, a), (b_later, b)| {
+            (a_later, a.name.len(), &a.name).cmp(&(b_later, b.name.len(), &b.name))
+        });
+        found
+            .into_iter()
+            .take(limit)
+            .map(|(_, label)| label.clone())
+            .collect()
+    }
+
+    /// Token contracts with this exact symbol, case insensitive
+    pub fn by_symbol(&self, symbol: &str) -> Vec<PublicLabel> {
+        let symbol = symbol.trim();
+        let loaded = self.loaded.read().unwrap();
+        let mut found: Vec<PublicLabel> = loaded
+            .labels
+            .values()
+            .filter(|label| {
+                label
+                    .symbol
+                    .as_deref()
+                    .is_some_and(|s| s.eq_ignore_ascii_case(symbol))
+            })
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| a.name.cmp(&b.name));
+        found
+    }
+
+    pub fn status(&self) -> RegistryStatus {
+        let loaded = self.loaded.read().unwrap();
+        RegistryStatus {
+            path: self.path.as_ref().map(|p| p.display().to_string()),
+            labels: loaded.labels.len(),
+            loaded_at: loaded.loaded_at,
+            last_error: loaded.last_error.clone(),
+        }
+    }
+}
+
+/// `public_label(address=...)` in the templates, the label of an address or null
+impl rocket_dyn_templates::tera::Function for LabelRegistry {
+    fn call(
+        &self,
+        args: &HashMap<String, serde_json::Value>,
+    ) -> rocket_dyn_templates::tera::Result<serde_json::Value> {
+        let label = args
+            .get("address")
+            .and_then(|a| a.as_str())
+            .and_then(|a| self.get(a));
+        Ok(serde_json::to_value(label)?)
+    }
+
+    fn is_safe(&self) -> bool {
+        false
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn normalize(address: &str) -> Result<String, String> {
+    address
+        .trim()
+        .parse::<H160>()
+        .map(|a| format!("{:?}", a))
+        .map_err(|_| format!("invalid address {}", address))
+}
+
+/// Labels by lowercase address, CSV for `.csv` files and JSON otherwise
+fn parse_file(path: &std::path::Path) -> Result<HashMap<String, PublicLabel>, Error> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::Parse(format!("Unable to read {}: {}", path.display(), e)))?;
+    let labels = if path.extension().and_then(|e| e.to_str()) == Some("csv") {
+        parse_csv(&content)
+    } else {
+        parse_json(&content)
+    }
+    .map_err(|e| Error::Parse(format!("Invalid label file {}: {}", path.display(), e)))?;
+    Ok(labels
+        .into_iter()
+        .map(|label| (label.address.clone(), label))
+        .collect())
+}
+
+/// Either a list of `{"address", "name", "category"}` objects,
+/// or an object mapping addresses to a name or to `{"name", "category", "symbol"}`
+fn parse_json(content: &str) -> Result<Vec<PublicLabel>, String> {
+    #[derive(Deserialize)]
+    #[serde(crate = "rocket::serde")]
+    #[serde(untagged)]
+    enum Entry {
+        Name(String),
+        Label {
+            name: String,
+            #[serde(default)]
+            category: Option<String>,
+            #[serde(default)]
+            symbol: Option<String>,
+        },
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "rocket::serde")]
+    #[serde(untagged)]
+    enum File {
+        List(Vec<PublicLabel>),
+        Map(HashMap<String, Entry>),
+    }
+
+    let labels = match serde_json::from_str(content).map_err(|e| e.to_string())? {
+        File::List(labels) => labels,
+        File::Map(map) => map
+            .into_iter()
+            .map(|(address, entry)| match entry {
+                Entry::Name(name) => PublicLabel {
+                    address,
+                    name,
+                    category: None,
+                    symbol: None,
+                },
+                Entry::Label {
+                    name,
+                    category,
+                    symbol,
+                } => PublicLabel {
+                    address,
+                    name,
+                    category,
+                    symbol,
+                },
+            })
+            .collect(),
+    };
+    labels
+        .into_iter()
+        .map(|label| {
+            Ok(PublicLabel {
+                address: normalize(&label.address)?,
+                ..label
+            })
+        })
+        .collect()
+}
+
+/// `address,name[,category[,symbol]]` lines, an optional header line starts with `address`.
+/// Fields can be double quoted to hold commas, `""` is a quote inside them.
+fn parse_csv(content: &str) -> Result<Vec<PublicLabel>, String> {
+    let mut labels = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let fields = csv_fields(line);
+        match fields.first().map(|f| f.trim().to_lowercase()) {
+            None => continue,
+            Some(first) if first.is_empty() || first == "address" || first.starts_with('#') => {
+                continue
+            }
+            _ => {}
+        }
+        let name = fields
+            .get(1)
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .ok_or_else(|| format!("line {} has no name", number + 1))?;
+        labels.push(PublicLabel {
+            address: normalize(&fields[0]).map_err(|e| format!("line {}: {}", number + 1, e))?,
+            name,
+            category: fields
+                .get(2)
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty()),
+            symbol: fields
+                .get(3)
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty()),
+        });
+    }
+    Ok(labels)
+}
+
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+    }
+    fields
+}
@@ -1 +1,350 @@
+use crate::error::{ApiError, Error};
+use crate::{EtherClient, State};
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// Private cookie holding the session
+const SESSION_COOKIE: &str = "session";
+
+/// How long an issued nonce can be used to sign in
+const NONCE_TTL: Duration = Duration::from_secs(600);
+
+/// Nonces waiting to be signed, beyond this the oldest are dropped
+const MAX_NONCES: usize = 10_000;
+
+/// Sign-In with Ethereum settings
+#[derive(clap::Args, Debug, Clone)]
+pub struct LoginConfig {
+    /// Domain expected in SIWE messages, the Host of the request by default
+    #[clap(long, env = "SIWE_DOMAIN")]
+    pub siwe_domain: Option<String>,
+    /// Prefix expected for the URI of SIWE messages, any URI on the domain by default
+    #[clap(long, env = "SIWE_URI")]
+    pub siwe_uri: Option<String>,
+    /// Chain id expected in SIWE messages, the node's by default
+    #[clap(long, env = "SIWE_CHAIN_ID")]
+    pub siwe_chain_id: Option<u64>,
+    /// Hours a session lasts, unless the signed message expires earlier
+    #[clap(long, default_value_t = 24)]
+    pub session_hours: u64,
+    /// Addresses allowed on the admin pages once signed in, comma separated
+    #[clap(long = "admin", env = "ADMIN_ADDRESSES", use_value_delimiter = true)]
+    pub admins: Vec<String>,
+    /// Token allowing scripts on the admin endpoints, sent as `Authorization: Bearer <token>`
+    #[clap(long, env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+}
+
+impl LoginConfig {
+    fn domain(&self, host: Host) -> Result<String, Error> {
+        self.siwe_domain
+            .clone()
+            .or(host.0)
+            .ok_or_else(|| Error::Parse("No domain to check the message against".to_string()))
+    }
+
+    async fn chain_id(&self, client: &EtherClient) -> Result<u64, Error> {
+        match self.siwe_chain_id {
+            Some(chain_id) => Ok(chain_id),
+            None => Ok(client.web3.eth().chain_id().await?.as_u64()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct LoginRequest {
+    /// The EIP-4361 message, as signed
+    pub message: String,
+    /// The `personal_sign` signature, 65 hex encoded bytes
+    pub signature: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NonceResponse {
+    pub nonce: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MessageResponse {
+    pub nonce: String,
+    /// The EIP-4361 message to sign
+    pub message: String,
+}
+
+/// The signed-in address, kept in the session cookie.
+/// As a request guard it fails with 401 without a valid session,
+/// use `Option<User>` to only read it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct User {
+    pub address: String,
+    /// Unix time the session ends
+    pub expires_at: i64,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for User {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let user = request
+            .cookies()
+            .get_private(SESSION_COOKIE)
+            .and_then(|cookie| serde_json::from_str::<User>(cookie.value()).ok())
+            .filter(|user| user.expires_at > now());
+        match user {
+            Some(user) => Outcome::Success(user),
+            None => Outcome::Failure((
+                Status::Unauthorized,
+                Error::Unauthorized("Not signed in".to_string()),
+            )),
+        }
+    }
+}
+
+/// An admin of the explorer: signed in with an address given to `--admin`,
+/// or sending the `--admin-token`. As a request guard it fails with 403 otherwise,
+/// admin endpoints are closed to everyone when neither is configured.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let forbidden = || {
+            Outcome::Failure((
+                Status::Forbidden,
+                Error::Forbidden("Reserved to the admins".to_string()),
+            ))
+        };
+        let config = match request.rocket().state::<LoginConfig>() {
+            Some(config) => config,
+            None => return forbidden(),
+        };
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        if let (Some(expected), Some(token)) = (&config.admin_token, token) {
+            if !expected.is_empty() && same_secret(expected.as_bytes(), token.trim().as_bytes()) {
+                return Outcome::Success(Admin);
+            }
+        }
+        match request.guard::<User>().await {
+            Outcome::Success(user)
+                if config
+                    .admins
+                    .iter()
+                    .any(|admin| admin.trim().eq_ignore_ascii_case(&user.address)) =>
+            {
+                Outcome::Success(Admin)
+            }
+            _ => forbidden(),
+        }
+    }
+}
+
+/// Compare secrets in a time that does not depend on where they differ
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Nonces issued by the server, each one can sign in once
+#[derive(Default)]
+pub struct Nonces {
+    issued: Mutex<HashMap<String, Instant>>,
+}
+
+impl Nonces {
+    pub fn issue(&self) -> String {
+        let nonce = siwe::nonce::generate_nonce();
+        let mut issued = self.issued.lock().unwrap();
+        issued.retain(|_, at| at.elapsed() < NONCE_TTL);
+        if issued.len() >= MAX_NONCES {
+            if let Some(oldest) = issued
+                .iter()
+                .min_by_key(|(_, at)| **at)
+                .map(|(n, _)| n.clone())
+            {
+                issued.remove(&oldest);
+            }
+        }
+        issued.insert(nonce.clone(), Instant::now());
+        nonce
+    }
+
+    /// Use up a nonce, false if it was never issued, already used or expired
+    fn consume(&self, nonce: &str) -> bool {
+        self.issued
+            .lock()
+            .unwrap()
+            .remove(nonce)
+            .is_some_and(|at| at.elapsed() < NONCE_TTL)
+    }
+}
+
+/// Host the request was sent to, the default SIWE domain
+pub struct Host(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Host {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Host(request.headers().get_one("Host").map(str::to_string)))
+    }
+}
+
+/// A fresh nonce to put in the SIWE message
+#[get("/login/nonce")]
+pub async fn nonce(nonces: &State<Nonces>) -> Json<NonceResponse> {
+    Json(NonceResponse {
+        nonce: nonces.issue(),
+    })
+}
+
+/// A SIWE message for `address` with a fresh nonce, ready to be signed by a wallet.
+/// It can be used until the nonce expires.
+#[get("/login/message?<address>")]
+pub async fn message(
+    address: &str,
+    host: Host,
+    config: &State<LoginConfig>,
+    nonces: &State<Nonces>,
+    client: &State<EtherClient>,
+) -> Result<Json<MessageResponse>, ApiError> {
+    let address = hex::decode(address.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| <[u8; 20]>::try_from(bytes).ok())
+        .ok_or_else(|| Error::Parse(format!("Invalid address {}", address)))?;
+    let domain = config.domain(host)?;
+    let uri = config
+        .siwe_uri
+        .clone()
+        .unwrap_or_else(|| format!("http://{}/", domain));
+    let chain_id = config.chain_id(client).await?;
+    let nonce = nonces.issue();
+    let issued_at = rocket::time::OffsetDateTime::now_utc()
+        .format(&rocket::time::format_description::well_known::Rfc3339)
+        .map_err(|e| Error::Parse(format!("{}", e)))?;
+    let message = format!(
+        concat!(
+            "{domain} wants you to sign in with your Ethereum account:\n",
+            "{address}\n\n",
+            "Sign in to Etherglass\n\n",
+            "URI: {uri}\n",
+            "Version: 1\n",
+            "Chain ID: {chain_id}\n",
+            "Nonce: {nonce}\n",
+            "Issued At: {issued_at}",
+        ),
+        domain = domain,
+        address = siwe::eip55(&address),
+        uri = uri,
+        chain_id = chain_id,
+        nonce = nonce,
+        issued_at = issued_at,
+    );
+    // Parsing checks that the domain and URI make a valid message
+    let message: siwe::Message = message
+        .parse()
+        .map_err(|e| Error::Parse(format!("Unable to build the SIWE message: {}", e)))?;
+
+    Ok(Json(MessageResponse {
+        nonce,
+        message: message.to_string(),
+    }))
+}
+
+/// Check a signed SIWE message and open a session for its address
+#[post("/login", format = "application/json", data = "<data>")]
+pub async fn login(
+    data: Json<LoginRequest>,
+    host: Host,
+    config: &State<LoginConfig>,
+    nonces: &State<Nonces>,
+    client: &State<EtherClient>,
+    cookies: &CookieJar<'_>,
+) -> Result<Json<User>, ApiError> {
+    let message: siwe::Message = data
+        .message
+        .parse()
+        .map_err(|e| Error::Parse(format!("Invalid SIWE message: {}", e)))?;
+    let signature = hex::decode(data.signature.trim_start_matches("0x"))
+        .ok()
+        .and_then(|bytes| <[u8; 65]>::try_from(bytes).ok())
+        .ok_or_else(|| Error::Parse("The signature is not 65 hex encoded bytes".to_string()))?;
+
+    let domain = config.domain(host)?;
+    if !message.domain.as_str().eq_ignore_ascii_case(&domain) {
+        return Err(Error::Unauthorized(format!("The message is for {}", message.domain)).into());
+    }
+    let uri = message.uri.as_str();
+    let uri_matches = match &config.siwe_uri {
+        Some(prefix) => uri.starts_with(prefix.as_str()),
+        None => uri
+            .split_once("://")
+            .and_then(|(_, rest)| rest.split('/').next())
+            .is_some_and(|authority| authority.eq_ignore_ascii_case(&domain)),
+    };
+    if !uri_matches {
+        return Err(Error::Unauthorized(format!("Unexpected URI {}", uri)).into());
+    }
+    let chain_id = config.chain_id(client).await?;
+    if message.chain_id != chain_id {
+        return Err(
+            Error::Unauthorized(format!("The message is for chain {}", message.chain_id)).into(),
+        );
+    }
+    // Checked before the nonce is used up, a bad signature does not burn it
+    message
+        .verify(signature, None, None, None)
+        .map_err(|e| Error::Unauthorized(format!("{}", e)))?;
+    if !nonces.consume(&message.nonce) {
+        return Err(Error::Unauthorized("Unknown or expired nonce".to_string()).into());
+    }
+
+    let mut expires_at = now() + config.session_hours as i64 * 3600;
+    if let Some(expiration) = &message.expiration_time {
+        expires_at = expires_at.min(expiration.as_ref().timestamp());
+    }
+    let user = User {
+        address: format!("0x{}", hex::encode(message.address)),
+        expires_at,
+    };
+    let mut cookie = Cookie::new(
+        SESSION_COOKIE,
+        serde_json::to_string(&user).map_err(Error::from)?,
+    );
+    cookie.set_expires(rocket::time::OffsetDateTime::from_unix_timestamp(expires_at).ok());
+    cookies.add_private(cookie);
+    println!("Signed in {}", user.address);
+
+    Ok(Json(user))
+}
+
+/// The signed-in address, 401 without a session
+#[get("/login/session")]
+pub async fn session(user: User) -> Json<User> {
+    Json(user)
+}
+
+#[post("/logout")]
+pub async fn logout(cookies: &CookieJar<'_>) -> Status {
+    cookies.remove_private(Cookie::named(SESSION_COOKIE));
+    Status::NoContent
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
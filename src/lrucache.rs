@@ -0,0 +1,490 @@
+use crate::cache::{BackendStats, CacheBackend, CacheOp};
+use crate::error::Error;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// In-process backend keeping at most `capacity` cached values and, when set, `max_bytes`
+/// of cached keys and values. The least recently used cached value is evicted first.
+/// Values stored with `set` and `batch` (address index, crawler state, user data) are
+/// kept apart and never evicted.
+pub struct LruCache {
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    /// Cached node responses
+    lru: lru::LruCache<String, Entry>,
+    /// Everything else
+    kept: HashMap<String, Entry>,
+    /// Approximate size of the cached keys and values
+    bytes: usize,
+    /// Approximate size of the kept keys and values
+    kept_bytes: usize,
+    /// 0 for no limit
+    max_bytes: usize,
+    evicted: u64,
+}
+
+struct Entry {
+    stored: Stored,
+    expires: Option<Instant>,
+}
+
+enum Stored {
+    Value(String),
+    Fields(HashMap<String, String>),
+    /// Members sorted by (score, member), plus the score of every member
+    Scored {
+        sorted: Vec<(f64, String)>,
+        scores: HashMap<String, f64>,
+    },
+}
+
+impl Entry {
+    fn expired(&self) -> bool {
+        self.expires.is_some_and(|e| e <= Instant::now())
+    }
+
+    fn size(&self) -> usize {
+        match &self.stored {
+            Stored::Value(v) => v.len(),
+            Stored::Fields(map) => map.iter().map(|(k, v)| k.len() + v.len()).sum(),
+            Stored::Scored { sorted, .. } => sorted.iter().map(|(_, m)| m.len() + 8).sum(),
+        }
+    }
+}
+
+impl Entries {
+    /// Store a cached value, evicting the least recently used ones over the budget
+    fn insert_cached(&mut self, key: String, entry: Entry) {
+        self.remove_kept(&key);
+        self.bytes += key.len() + entry.size();
+        let replaced_key = key.clone();
+        // push gives back the replaced value, or the least recently used one when full
+        if let Some((k, old)) = self.lru.push(key, entry) {
+            self.bytes -= k.len() + old.size();
+            if k != replaced_key {
+                self.evicted += 1;
+            }
+        }
+        self.enforce_budget();
+    }
+
+    fn insert(&mut self, key: String, entry: Entry) {
+        if let Some(old) = self.lru.pop(&key) {
+            self.bytes -= key.len() + old.size();
+        }
+        self.kept_bytes += key.len() + entry.size();
+        if let Some(old) = self.kept.insert(key.clone(), entry) {
+            self.kept_bytes -= key.len() + old.size();
+        }
+    }
+
+    fn remove_kept(&mut self, key: &str) {
+        if let Some(old) = self.kept.remove(key) {
+            self.kept_bytes -= key.len() + old.size();
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(old) = self.lru.pop(key) {
+            self.bytes -= key.len() + old.size();
+        }
+        self.remove_kept(key);
+    }
+
+    /// The live entry at key, dropping it first when expired
+    fn live(&mut self, key: &str) -> Option<&Entry> {
+        let expired = self.lru.peek(key).or_else(|| self.kept.get(key));
+        if expired.is_some_and(Entry::expired) {
+            self.remove(key);
+        }
+        if self.lru.contains(key) {
+            self.lru.get(key)
+        } else {
+            self.kept.get(key)
+        }
+    }
+
+    /// Change the kept entry at key in place, replacing it with `new()` first
+    /// when it is missing or of another kind
+    fn update(
+        &mut self,
+        key: String,
+        is_kind: impl Fn(&Stored) -> bool,
+        new: impl FnOnce() -> Stored,
+        f: impl FnOnce(&mut Stored),
+    ) {
+        if !self.kept.get(&key).is_some_and(|e| is_kind(&e.stored)) {
+            self.insert(
+                key.clone(),
+                Entry {
+                    stored: new(),
+                    expires: None,
+                },
+            );
+        }
+        if let Some(entry) = self.kept.get_mut(&key) {
+            let before = entry.size();
+            f(&mut entry.stored);
+            self.kept_bytes = self.kept_bytes + entry.size() - before;
+        }
+    }
+
+    fn enforce_budget(&mut self) {
+        while self.max_bytes > 0 && self.bytes > self.max_bytes {
+            match self.lru.pop_lru() {
+                Some((k, old)) => {
+                    self.bytes -= k.len() + old.size();
+                    self.evicted += 1;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl LruCache {
+    pub fn new(capacity: usize, max_bytes: usize) -> LruCache {
+        LruCache {
+            entries: Mutex::new(Entries {
+                lru: lru::LruCache::new(capacity.max(1)),
+                kept: HashMap::new(),
+                bytes: 0,
+                kept_bytes: 0,
+                max_bytes,
+                evicted: 0,
+            }),
+        }
+    }
+
+    /// Run f on the live entry at key, dropping it first when expired
+    fn with_entry<T>(&self, key: &str, f: impl FnOnce(Option<&Entry>) -> T) -> T {
+        f(self.entries.lock().unwrap().live(key))
+    }
+
+    fn apply(entries: &mut Entries, op: CacheOp) {
+        match op {
+            CacheOp::Set { key, value, ttl } => {
+                entries.insert(
+                    key,
+                    Entry {
+                        stored: Stored::Value(value),
+                        expires: ttl.map(|ttl| Instant::now() + ttl),
+                    },
+                );
+            }
+            CacheOp::Delete { key } => entries.remove(&key),
+            CacheOp::SetFields { key, fields } => entries.update(
+                key,
+                |s| matches!(s, Stored::Fields(_)),
+                || Stored::Fields(HashMap::new()),
+                |s| {
+                    if let Stored::Fields(map) = s {
+                        map.extend(fields);
+                    }
+                },
+            ),
+            CacheOp::AddScored { key, member, score } => entries.update(
+                key,
+                |s| matches!(s, Stored::Scored { .. }),
+                || Stored::Scored {
+                    sorted: vec![],
+                    scores: HashMap::new(),
+                },
+                |s| {
+                    if let Stored::Scored { sorted, scores } = s {
+                        if let Some(old) = scores.insert(member.clone(), score) {
+                            sorted.retain(|(s, m)| !(*s == old && *m == member));
+                        }
+                        let at = sorted.partition_point(|(s, m)| (*s, m) < (score, &member));
+                        sorted.insert(at, (score, member));
+                    }
+                },
+            ),
+            CacheOp::RemoveScored { key, member } => {
+                if let Some(entry) = entries.kept.get_mut(&key) {
+                    let before = entry.size();
+                    if let Stored::Scored { sorted, scores } = &mut entry.stored {
+                        if let Some(old) = scores.remove(&member) {
+                            sorted.retain(|(s, m)| !(*s == old && *m == member));
+                        }
+                    }
+                    let after = entry.size();
+                    entries.kept_bytes = entries.kept_bytes + after - before;
+                }
+            }
+        }
+    }
+}
+
+/// Members of a sorted set within [min, max], in ascending order
+fn in_range(sorted: &[(f64, String)], min: f64, max: f64) -> &[(f64, String)] {
+    let start = sorted.partition_point(|(s, _)| *s < min);
+    let end = sorted.partition_point(|(s, _)| *s <= max);
+    &sorted[start..end.max(start)]
+}
+
+#[async_trait::async_trait]
+impl CacheBackend for LruCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        Ok(self.with_entry(key, |entry| match entry {
+            Some(Entry {
+                stored: Stored::Value(v),
+                ..
+            }) => Some(v.clone()),
+            _ => None,
+        }))
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error> {
+        self.batch(vec![CacheOp::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+            ttl,
+        }])
+        .await
+    }
+
+    async fn cache(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error> {
+        self.entries.lock().unwrap().insert_cached(
+            key.to_string(),
+            Entry {
+                stored: Stored::Value(value.to_string()),
+                expires: ttl.map(|ttl| Instant::now() + ttl),
+            },
+        );
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.with_entry(key, |entry| entry.is_some()))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, Error> {
+        Ok(self.with_entry(key, |entry| {
+            entry
+                .and_then(|e| e.expires)
+                .map(|e| e.saturating_duration_since(Instant::now()))
+        }))
+    }
+
+    async fn batch(&self, ops: Vec<CacheOp>) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        for op in ops {
+            LruCache::apply(&mut entries, op);
+        }
+        Ok(())
+    }
+
+    async fn fields(&self, keys: &[String]) -> Result<Vec<HashMap<String, String>>, Error> {
+        Ok(keys
+            .iter()
+            .map(|key| {
+                self.with_entry(key, |entry| match entry {
+                    Some(Entry {
+                        stored: Stored::Fields(map),
+                        ..
+                    }) => map.clone(),
+                    _ => HashMap::new(),
+                })
+            })
+            .collect())
+    }
+
+    async fn range_by_score(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+        ascending: bool,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        Ok(self.with_entry(key, |entry| match entry {
+            Some(Entry {
+                stored: Stored::Scored { sorted, .. },
+                ..
+            }) => {
+                let members = in_range(sorted, min, max).iter().map(|(_, m)| m.clone());
+                if ascending {
+                    members.skip(offset).take(count).collect()
+                } else {
+                    members.rev().skip(offset).take(count).collect()
+                }
+            }
+            _ => vec![],
+        }))
+    }
+
+    async fn count_by_score(&self, key: &str, min: f64, max: f64) -> Result<usize, Error> {
+        Ok(self.with_entry(key, |entry| match entry {
+            Some(Entry {
+                stored: Stored::Scored { sorted, .. },
+                ..
+            }) => in_range(sorted, min, max).len(),
+            _ => 0,
+        }))
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .lru
+            .iter()
+            .chain(entries.kept.iter())
+            .filter(|(k, e)| k.starts_with(prefix) && !e.expired())
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+
+    async fn stats(&self) -> Result<BackendStats, Error> {
+        let entries = self.entries.lock().unwrap();
+        Ok(BackendStats {
+            keys: (entries.lru.len() + entries.kept.len()) as u64,
+            bytes: (entries.bytes + entries.kept_bytes) as u64,
+            cached_bytes: entries.bytes as u64,
+            evicted: entries.evicted,
+        })
+    }
+
+    async fn maintain(&self) -> Result<(), Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let expired: Vec<String> = entries
+            .lru
+            .iter()
+            .chain(entries.kept.iter())
+            .filter(|(_, e)| e.expired())
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in expired {
+            entries.remove(&key);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LruCache;
+    use crate::cache::{CacheBackend, CacheOp};
+    use std::time::Duration;
+
+    fn add(key: &str, member: &str, score: f64) -> CacheOp {
+        CacheOp::AddScored {
+            key: key.to_string(),
+            member: member.to_string(),
+            score,
+        }
+    }
+
+    #[rocket::async_test]
+    async fn values_expire() {
+        let cache = LruCache::new(10, 0);
+        cache.set("kept", "1", None).await.unwrap();
+        cache
+            .set("short", "2", Some(Duration::from_millis(50)))
+            .await
+            .unwrap();
+        assert_eq!(cache.get("kept").await.unwrap().as_deref(), Some("1"));
+        assert_eq!(cache.get("short").await.unwrap().as_deref(), Some("2"));
+        assert_eq!(cache.ttl("kept").await.unwrap(), None);
+        assert!(cache.ttl("short").await.unwrap().is_some());
+
+        rocket::tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.get("short").await.unwrap(), None);
+        assert!(!cache.exists("short").await.unwrap());
+        cache.delete("kept").await.unwrap();
+        assert_eq!(cache.get("kept").await.unwrap(), None);
+    }
+
+    #[rocket::async_test]
+    async fn batch_and_scored_sets() {
+        let cache = LruCache::new(10, 0);
+        cache
+            .batch(vec![
+                CacheOp::Set {
+                    key: "value".to_string(),
+                    value: "v".to_string(),
+                    ttl: None,
+                },
+                CacheOp::SetFields {
+                    key: "map".to_string(),
+                    fields: vec![("a".to_string(), "1".to_string())],
+                },
+                add("set", "c", 3.0),
+                add("set", "a", 1.0),
+                add("set", "b", 2.0),
+                add("set", "d", 4.0),
+                CacheOp::RemoveScored {
+                    key: "set".to_string(),
+                    member: "d".to_string(),
+                },
+                // A member added again moves to its new score
+                add("set", "a", 5.0),
+                CacheOp::Delete {
+                    key: "value".to_string(),
+                },
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(cache.get("value").await.unwrap(), None);
+        let keys = ["map".to_string(), "none".to_string()];
+        let fields = cache.fields(&keys).await.unwrap();
+        assert_eq!(fields[0].get("a").map(String::as_str), Some("1"));
+        assert!(fields[1].is_empty());
+
+        let range = |ascending, offset, count| {
+            cache.range_by_score("set", 2.0, 5.0, ascending, offset, count)
+        };
+        assert_eq!(range(true, 0, 10).await.unwrap(), ["b", "c", "a"]);
+        assert_eq!(range(false, 0, 10).await.unwrap(), ["a", "c", "b"]);
+        assert_eq!(range(true, 1, 1).await.unwrap(), ["c"]);
+        assert_eq!(cache.count_by_score("set", 2.0, 3.0).await.unwrap(), 2);
+        assert_eq!(cache.count_by_score("none", 0.0, 9.0).await.unwrap(), 0);
+    }
+
+    #[rocket::async_test]
+    async fn evicts_least_recently_used_responses_only() {
+        // Each response takes 2 bytes of key and 8 of value
+        let cache = LruCache::new(10, 25);
+        cache.set("kept", &"x".repeat(100), None).await.unwrap();
+        cache.cache("r1", "12345678", None).await.unwrap();
+        cache.cache("r2", "12345678", None).await.unwrap();
+        // Used, r2 becomes the least recently used
+        cache.get("r1").await.unwrap();
+        cache.cache("r3", "12345678", None).await.unwrap();
+
+        assert!(cache.exists("kept").await.unwrap());
+        assert!(cache.exists("r1").await.unwrap());
+        assert!(!cache.exists("r2").await.unwrap());
+        assert!(cache.exists("r3").await.unwrap());
+        let stats = cache.stats().await.unwrap();
+        assert_eq!(stats.cached_bytes, 20);
+        assert_eq!(stats.evicted, 1);
+
+        // Stored again with set, a response is no longer evictable
+        cache.set("r1", "12345678", None).await.unwrap();
+        cache.cache("r4", "12345678", None).await.unwrap();
+        cache.cache("r5", "12345678", None).await.unwrap();
+        assert!(cache.exists("r1").await.unwrap());
+        assert!(!cache.exists("r3").await.unwrap());
+    }
+
+    #[rocket::async_test]
+    async fn evicts_over_capacity() {
+        let cache = LruCache::new(2, 0);
+        for key in ["r1", "r2", "r3"] {
+            cache.cache(key, "1", None).await.unwrap();
+        }
+        assert!(!cache.exists("r1").await.unwrap());
+        assert!(cache.exists("r3").await.unwrap());
+    }
+}
@@ -1,20 +1,31 @@
 #[macro_use]
 extern crate rocket;
-#[macro_use]
-extern crate lazy_static;
 
 use rocket::response::stream::{Event, EventStream};
-use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::serde::{json::Json, Deserialize};
 use rocket::tokio::select;
 use rocket::tokio::sync::broadcast::{channel, error::RecvError, Sender};
-use std::i64;
 use web3::api::Namespace;
 
-// pub mod login (TODO);
+pub mod cache;
 pub mod crawler;
+pub mod decoder;
+pub mod diskcache;
+pub mod ens;
+pub mod error;
+pub mod follower;
+pub mod labels;
+pub mod login;
+pub mod lrucache;
+pub mod mempool;
+pub mod node;
 pub mod pages;
 pub mod parser;
 pub mod rcache;
+pub mod reorg;
+pub mod userdata;
+
+pub use cache::Cache;
 
 #[derive(Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -24,25 +35,6 @@ pub struct RequestData {
 
 use rocket_dyn_templates::Template;
 
-#[derive(Deserialize)]
-#[serde(crate = "rocket::serde")]
-pub struct LoginRequest {
-    pub signature: String,
-    pub message: String,
-}
-
-#[derive(Serialize)]
-#[serde(crate = "rocket::serde")]
-pub struct LoginResponse {
-    pub address: String,
-}
-
-#[derive(Serialize)]
-#[serde(crate = "rocket::serde")]
-pub struct StatusResponse {
-    pub status: bool,
-}
-
 /// This interprets JSON-RPC api methods retrieving data from the node.
 /// Allowed methods are in parser::parse_request method.
 #[post("/<api>/<method>", format = "application/json", data = "<option_data>")]
@@ -50,7 +42,10 @@ async fn api(
     api: &str,
     method: &str,
     option_data: Option<Json<RequestData>>,
-) -> Json<parser::ResponseData> {
+    client: &State<EtherClient>,
+    cache: &State<Cache>,
+    abi_registry: &State<decoder::AbiRegistry>,
+) -> Result<Json<parser::ResponseData>, error::ApiError> {
     let mut data = RequestData {
         data: serde_json::json!({}),
     };
@@ -59,47 +54,25 @@ async fn api(
         data = option_data.into_inner();
     }
 
-    let result = parser::parse_request(api, method, data).await;
-
-    Json(result)
-}
-
-/// Currently unused Sign In with Ethereum endpoint (TODO)
-#[post("/login", format = "application/json", data = "<data>")]
-async fn web3_login(data: Json<LoginRequest>) -> Json<LoginResponse> {
-    let message: siwe::Message = data.message.parse().unwrap();
-    let bytes_signature = data.signature.as_bytes();
-
-    let mut signature_bytes = [0u8; 65];
-    for i in 0..65 {
-        signature_bytes[i] = bytes_signature[i];
+    let mut result = parser::parse_request(client, cache, api, method, data).await?;
+    if method == "transaction" {
+        abi_registry
+            .annotate_transaction(&mut result.data["transaction"])
+            .map_err(error::ApiError)?;
     }
 
-    if let Err(_e) = message.verify(signature_bytes, None, None, None) {
-        // message cannot be correctly authenticated at this time
-        return Json(LoginResponse {
-            address: String::from("0x0"),
-        });
-    }
-
-    Json(LoginResponse {
-        address: format!("{:x?}", message.address),
-    })
-}
-
-#[derive(Debug, Clone, FromForm, Serialize, Deserialize)]
-#[cfg_attr(test, derive(PartialEq, UriDisplayQuery))]
-#[serde(crate = "rocket::serde")]
-struct BlockEvent {
-    pub blocks: Vec<pages::home::SimpleBlock>,
+    Ok(Json(result))
 }
 
 use rocket::{Shutdown, State};
 
 /// Returns an infinite stream of server-sent events. Each event is a message
-/// Returns the latest pages::home::LATEST_BLOCKS_AMOUNT blocks as sse events every 10 seconds
+/// Sends one event per new block, as published by the head follower
 #[get("/latest_blocks")]
-async fn latest_blocks(queue: &State<Sender<BlockEvent>>, mut end: Shutdown) -> EventStream![] {
+async fn latest_blocks(
+    queue: &State<Sender<follower::BlockEvent>>,
+    mut end: Shutdown,
+) -> EventStream![] {
     let mut rx = queue.subscribe();
     EventStream! {
         loop {
@@ -117,77 +90,23 @@ async fn latest_blocks(queue: &State<Sender<BlockEvent>>, mut end: Shutdown) ->
     }
 }
 
-use std::sync::Mutex;
-use std::sync::RwLock;
-
-lazy_static! {
-    static ref IS_POLLING_EVENTS: RwLock<bool> = RwLock::new(false);
-    static ref REDIS_CACHE: Mutex<bool> = Mutex::new(false);
+/// Health of every configured RPC endpoint
+#[get("/status/endpoints")]
+async fn endpoints_status(client: &State<EtherClient>) -> Json<Vec<node::EndpointStatus>> {
+    Json(client.web3.transport().status())
 }
 
-const POLLING_INTERVAL: u64 = 10;
-
-/// Receive a message from a form submission and broadcast it to any receivers.
-/// Activate the SSE events
-/// Retrieve the latest X blocks from the node and send them as SSE events
-#[get("/start_polling")]
-async fn start_polling(queue: &State<Sender<BlockEvent>>) -> Json<StatusResponse> {
-    // Poll for new incoming blocks
-    // Check if it's already polling in global Lazy static
-    let is_polling_events = IS_POLLING_EVENTS.read().unwrap().to_owned();
-    if is_polling_events {
-        // Already polling
-    } else {
-        std::thread::spawn(move || {
-            // Set global Lazy static to true
-            let mut new_settings = IS_POLLING_EVENTS.write().unwrap();
-            *new_settings = true;
-        });
-        loop {
-            // Retrieve latest X blocks
-            let block_number = &parser::parse_request(
-                "eth",
-                "blockNumber",
-                RequestData {
-                    data: serde_json::json!({}),
-                },
-            )
-            .await
-            .data["blockNumber"];
-            let b_n = json_value_hex_to_int(block_number.clone());
-
-            let latest_blocks: Vec<pages::home::SimpleBlock> =
-                pages::home::get_latest_blocks(b_n).await;
-
-            // Publish blocks to SSE clients
-            let _ = queue.send(BlockEvent {
-                blocks: latest_blocks,
-            });
-
-            // Get cache status
-            let redis_cache = crate::Cache {
-                enabled: *crate::REDIS_CACHE.lock().unwrap(),
-
-                // Temporary redefining it here, should be moved around coming from the Rocket handler
-                redis_client: Some(redis::Client::open("redis://localhost:6379").unwrap()),
-            };
-
-            if redis_cache.enabled {
-                // If cache is enable, crawl the block and cache addresses with corresponding transactions
-                let _ = pages::address::cache_addresses_transactions_from_block(b_n as i64).await;
-            }
-
-            std::thread::sleep(std::time::Duration::from_secs(POLLING_INTERVAL));
-        }
-    }
-
-    return Json(StatusResponse { status: true });
+/// Cache policy, hit rate and size of the backend
+#[get("/admin/cache")]
+async fn cache_stats(
+    _admin: login::Admin,
+    cache: &State<Cache>,
+) -> Result<Json<cache::CacheStats>, error::ApiError> {
+    Ok(Json(cache.stats().await?))
 }
 
-pub struct Cache {
-    enabled: bool,
-    redis_client: Option<redis::Client>,
-}
+/// Seconds between two cache maintenance runs
+const CACHE_MAINTENANCE_INTERVAL: u64 = 60;
 
 use clap::Parser;
 
@@ -195,79 +114,195 @@ use clap::Parser;
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Enable redis cache
+    /// Enable cache
     #[clap(long)]
     cache: bool,
+    /// Where the cache is kept
+    #[clap(long, arg_enum, default_value = "redis")]
+    cache_backend: cache::BackendKind,
+    /// Maximum amount of cached responses kept by the memory cache backend
+    #[clap(long, default_value_t = 100_000)]
+    cache_capacity: usize,
+    /// Directory of the disk cache backend
+    #[clap(long, default_value = "etherglass-cache")]
+    cache_path: String,
+    /// Blocks on top of a block before it is cached as final
+    #[clap(long, default_value_t = cache::DEFAULT_CONFIRMATIONS)]
+    confirmations: u64,
+    #[clap(flatten)]
+    cache_policy: cache::CachePolicy,
+    #[clap(flatten)]
+    redis: rcache::RedisConfig,
+    #[clap(flatten)]
+    login: login::LoginConfig,
     /// Enable crawler of past transaction
     #[clap(long)]
     crawler: bool,
-    /// Starting block for crawler
+    /// Starting block for crawler, latest-100 forward and the head backward by default
     #[clap(long)]
     start_block: Option<i64>,
-    /// Ending block for crawler
+    /// Ending block for crawler (inclusive), the head forward and genesis backward by default
     #[clap(long)]
     end_block: Option<i64>,
+    /// Direction of the crawl
+    #[clap(long, arg_enum, default_value = "forward")]
+    crawl_direction: crawler::Direction,
+    /// Blocks indexed at the same time by the crawler
+    #[clap(long, default_value_t = 8)]
+    crawler_concurrency: usize,
+    /// Passes over the blocks that failed to be crawled
+    #[clap(long, default_value_t = 3)]
+    crawler_retries: u32,
+    /// Blocks an RPC endpoint can lag behind the best head before it stops receiving requests
+    #[clap(long, default_value_t = 5)]
+    max_block_lag: u64,
+    /// Seconds between two polls of the head
+    #[clap(long, default_value_t = 10)]
+    polling_interval: u64,
+    /// Follow pending and queued transactions for the mempool viewer
+    #[clap(long)]
+    mempool: bool,
+    /// Seconds between two refreshes of the mempool
+    #[clap(long, default_value_t = 5)]
+    mempool_interval: u64,
+    /// Seconds between RPC endpoint health checks
+    #[clap(long, default_value_t = 10)]
+    health_check_interval: u64,
+    /// Directory of extra JSON ABIs used to decode logs
+    #[clap(long)]
+    abi_dir: Option<String>,
+    /// JSON or CSV file of public address labels, loaded again when it changes
+    #[clap(long, env = "LABELS_FILE")]
+    labels: Option<String>,
+    /// Seconds between two checks of the label file for changes
+    #[clap(long, default_value_t = 10)]
+    labels_reload_interval: u64,
+    /// Convert the address index of earlier versions to the current schema, then exit
+    #[clap(long)]
+    migrate_address_index: bool,
 }
 
 #[launch]
-fn rocket() -> _ {
+async fn rocket() -> _ {
     let args = Args::parse();
 
     println!("Enable cache: {}!", args.cache);
-    let enable_cache = args.cache;
-
-    let mut redis_cache = false;
-    if enable_cache {
-        redis_cache = true
+    let cache = if args.cache {
+        Cache::open(
+            args.cache_backend,
+            args.cache_capacity,
+            &args.cache_path,
+            &args.redis,
+            args.cache_policy.clone(),
+        )
+        .await
+        .expect("Unable to open the cache")
+    } else {
+        Cache::disabled(args.cache_policy.clone())
     }
+    .with_confirmations(args.confirmations);
 
-    let mut static_cache = REDIS_CACHE.lock().unwrap();
-    *static_cache = redis_cache;
-
-    // Define redis client
-    // If crawler is enabled, start it
-    // Starting block and ending block are optional
-    let redis_client: Option<redis::Client> = if redis_cache {
-        if args.crawler {
-            std::thread::spawn(move || {
-                let starting_block = if let Some(start_block) = args.start_block {
-                    start_block
-                } else {
-                    0
-                };
-                let ending_block = if let Some(end_block) = args.end_block {
-                    end_block
-                } else {
-                    0
-                };
-
-                if ending_block <= starting_block {
-                    println!("Ending block must be greater than starting block");
-                    return;
+    // One long-lived client shared by every request, the poller and the crawler
+    let ether_client = client().await.expect("Unable to connect to the node");
+
+    // Keep the endpoint health up to date so requests only go to caught-up nodes
+    let balanced = ether_client.web3.transport().clone();
+    let (max_block_lag, health_check_interval) = (args.max_block_lag, args.health_check_interval);
+    rocket::tokio::spawn(async move {
+        loop {
+            balanced.check_health(max_block_lag).await;
+            rocket::tokio::time::sleep(std::time::Duration::from_secs(health_check_interval)).await;
+        }
+    });
+
+    // Drop expired data and keep the cache within its size budget
+    if cache.enabled {
+        let maintained = cache.clone();
+        rocket::tokio::spawn(async move {
+            loop {
+                rocket::tokio::time::sleep(std::time::Duration::from_secs(
+                    CACHE_MAINTENANCE_INTERVAL,
+                ))
+                .await;
+                if let Ok(backend) = maintained.backend() {
+                    if let Err(e) = backend.maintain().await {
+                        println!("Cache maintenance failed: {}", e);
+                    }
                 }
-                async_std::task::block_on(async move {
-                    crawler::crawler(starting_block, ending_block).await;
-                });
-            });
+            }
+        });
+    }
+
+    if args.migrate_address_index {
+        match pages::address::migrate_address_index(&ether_client, &cache).await {
+            Ok(migrated) => println!("Migrated {} addresses", migrated),
+            Err(e) => println!("Address index migration failed: {}", e),
         }
-        Some(redis::Client::open("redis://localhost:6379").unwrap())
-    } else {
-        None
-    };
+        std::process::exit(0);
+    }
 
-    let cache = Cache {
-        enabled: redis_cache,
-        redis_client: redis_client,
-    };
+    // Crawls are started with --crawler or from the admin page
+    let crawler_control = crawler::CrawlerControl::new(crawler::CrawlOptions {
+        start_block: args.start_block,
+        end_block: args.end_block,
+        direction: args.crawl_direction,
+        concurrency: args.crawler_concurrency,
+        retries: args.crawler_retries,
+    });
+    if cache.enabled && args.crawler {
+        let options = crawler_control.defaults.clone();
+        if let Err(e) = crawler_control.start(ether_client.clone(), cache.clone(), options) {
+            println!("Unable to start the crawler: {}", e);
+        }
+    }
+
+    let mut abi_registry = decoder::AbiRegistry::new();
+    if let Some(abi_dir) = &args.abi_dir {
+        if let Err(e) = abi_registry.load_dir(abi_dir) {
+            println!("{}", e);
+        }
+    }
+
+    let label_registry = labels::LabelRegistry::new(args.labels.as_deref());
+    if args.labels.is_some() {
+        let watched = label_registry.clone();
+        let interval = std::time::Duration::from_secs(args.labels_reload_interval);
+        rocket::tokio::spawn(async move {
+            loop {
+                rocket::tokio::time::sleep(interval).await;
+                watched.reload_if_changed();
+            }
+        });
+    }
+    let template_labels = label_registry.clone();
 
     // Create a channel to send messages to the SSE clients
-    let queue = channel::<BlockEvent>(1024).0;
+    let queue = channel::<follower::BlockEvent>(1024).0;
+    let activity = channel::<follower::BlockActivity>(1024).0;
 
     // Start the SSE and API server
     rocket::build()
         .manage(queue)
+        .manage(activity)
         .manage(cache)
-        .attach(Template::fairing())
+        .manage(ether_client)
+        .manage(abi_registry)
+        .manage(label_registry)
+        .manage(crawler_control)
+        .manage(args.login)
+        .manage(login::Nonces::default())
+        .manage(mempool::Mempool::new(args.mempool))
+        .attach(Template::custom(move |engines| {
+            engines
+                .tera
+                .register_function("public_label", template_labels.clone());
+        }))
+        .attach(follower::fairing(std::time::Duration::from_secs(
+            args.polling_interval,
+        )))
+        .attach(mempool::fairing(std::time::Duration::from_secs(
+            args.mempool_interval,
+        )))
         .mount(
             "/",
             routes![
@@ -277,42 +312,179 @@ fn rocket() -> _ {
                 pages::block::block_hash,
                 pages::transaction::transaction,
                 pages::address::address,
-                web3_login,
+                login::nonce,
+                login::message,
+                login::login,
+                login::session,
+                login::logout,
                 latest_blocks,
-                start_polling
+                endpoints_status,
+                cache_stats,
+                pages::crawler::crawler,
+                pages::crawler::status,
+                pages::crawler::start,
+                pages::crawler::pause,
+                pages::crawler::resume,
+                pages::crawler::cancel,
+                pages::mempool::mempool,
+                pages::mempool::snapshot,
+                pages::mempool::stream,
+                pages::watch::address,
+                pages::watch::transaction,
+                pages::user::user,
+                pages::user::data,
+                pages::user::export,
+                pages::user::import,
+                pages::user::watch,
+                pages::user::unwatch,
+                pages::user::label,
+                pages::user::unlabel,
+                pages::labels::search,
+                pages::labels::status,
+                pages::labels::reload,
+                pages::search::search,
+                pages::search::suggest,
+                pages::api::api
             ],
         )
 }
 
 // Define web3 client for node connections
+// Cloning is cheap, clones share the same underlying transport and connection pool
+#[derive(Clone)]
 pub struct EtherClient {
-    web3: web3::Web3<web3::transports::Http>,
-    ens: web3::contract::ens::Ens<web3::transports::Http>,
+    web3: web3::Web3<node::Balanced>,
+    ens: web3::contract::ens::Ens<node::Balanced>,
 }
 
 /// Web3 client for node connections
+/// ETH_RPC_ENDPOINT is a comma separated list of endpoints, each one can be
+/// an http(s)://, ws(s)://, ipc:// endpoint or a path to an IPC socket
 async fn client() -> Result<EtherClient, String> {
     let rpc_endpoint = std::env::var("ETH_RPC_ENDPOINT").map_err(|e| format!("{}", e));
     let rpc_endpoint = rpc_endpoint.unwrap_or_else(|_| "http://localhost:8545".to_string());
-    let transport = web3::transports::Http::new(&rpc_endpoint).unwrap();
-    let web3 = web3::Web3::new(transport.clone());
-    let ens = web3::contract::ens::Ens::new(transport.clone());
-
-    Ok(EtherClient {
-        web3: web3,
-        ens: ens,
-    })
+    let rpc_endpoints: Vec<String> = rpc_endpoint
+        .split(',')
+        .map(|e| e.trim().to_string())
+        .filter(|e| !e.is_empty())
+        .collect();
+    EtherClient::connect(&rpc_endpoints).await
+}
+
+impl EtherClient {
+    /// Client balanced over the given endpoints
+    async fn connect(endpoints: &[String]) -> Result<EtherClient, String> {
+        let transport = node::Balanced::connect(endpoints).await?;
+        let web3 = web3::Web3::new(transport.clone());
+        let ens = web3::contract::ens::Ens::new(transport);
+
+        Ok(EtherClient { web3, ens })
+    }
 }
 
 /// Normalize hex strings to lowercase and remove 0x prefix
 /// TODO : This should be done by a proper library
-pub fn json_value_hex_to_int(h: serde_json::Value) -> i128 {
-    let input = h.as_str().unwrap().replace("0x", "").replace("\"", "");
-    let mut _output = 0;
-    _output = i128::from_str_radix(&input.to_string(), 16).unwrap();
-    _output
+pub fn json_value_hex_to_int(h: serde_json::Value) -> Result<i128, error::Error> {
+    let input = h
+        .as_str()
+        .ok_or_else(|| error::Error::Parse(format!("Expected a hex string, got {}", h)))?
+        .replace("0x", "")
+        .replace('"', "");
+    i128::from_str_radix(&input, 16)
+        .map_err(|_| error::Error::Parse(format!("Invalid hex number {}", input)))
+}
+
+/// Format a wei amount as ETH, without trailing zeros
+pub fn wei_to_eth(wei: web3::types::U256) -> String {
+    let (eth, rest) = wei.div_mod(web3::types::U256::exp10(18));
+    // rest < 10^18 always fits in a u128
+    let decimals = format!("{:018}", rest.as_u128());
+    let decimals = decimals.trim_end_matches('0');
+    if decimals.is_empty() {
+        eth.to_string()
+    } else {
+        format!("{}.{}", eth, decimals)
+    }
 }
 
 pub fn clean(s: String) -> String {
     s.replace("\"", "")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EtherClient;
+    use rocket::tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use rocket::tokio::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Answer the JSON-RPC requests of one keep-alive HTTP connection with block 0x10
+    async fn serve(stream: TcpStream) {
+        let mut stream = BufReader::new(stream);
+        loop {
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                    return;
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body).await.unwrap();
+            let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let response = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": "0x10",
+            })
+            .to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+                response.len(),
+                response
+            );
+            stream
+                .get_mut()
+                .write_all(response.as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    /// Mock node counting the connections it accepts
+    async fn mock_node() -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        rocket::tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                rocket::tokio::spawn(serve(stream));
+            }
+        });
+        (url, connections)
+    }
+
+    #[rocket::async_test]
+    async fn clients_share_one_connection() {
+        let (url, connections) = mock_node().await;
+        let client = EtherClient::connect(&[url]).await.unwrap();
+        for _ in 0..5 {
+            // Pages get clones of the managed client
+            let client = client.clone();
+            let head = client.web3.eth().block_number().await.unwrap();
+            assert_eq!(head.as_u64(), 0x10);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,470 @@
+use crate::error::Error;
+use crate::node::{self, TransportKind};
+use crate::{parser, Cache, EtherClient, RequestData};
+use rocket::fairing::AdHoc;
+use rocket::serde::Serialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{channel, Receiver, Sender};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use web3::futures::StreamExt;
+use web3::types::{Transaction, H256};
+
+/// Where the mempool view comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum MempoolSource {
+    /// Not enabled with `--mempool`
+    Disabled,
+    /// Not known yet
+    Starting,
+    /// `txpool_content`, pending and queued transactions
+    Txpool,
+    /// `eth_subscribe("newPendingTransactions")`, the pending transactions seen recently
+    Subscription,
+    /// The nodes support neither
+    Unavailable,
+}
+
+/// A transaction waiting in the mempool
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PendingTransaction {
+    pub hash: String,
+    pub from: String,
+    pub to: String,
+    pub nonce: u64,
+    /// In ETH
+    pub value: String,
+    /// Max fee per gas, or gas price for legacy transactions, in gwei
+    pub gas_price_gwei: f64,
+    /// Waiting for a lower nonce of the same sender
+    pub queued: bool,
+}
+
+impl PendingTransaction {
+    fn new(tx: &Transaction, queued: bool) -> PendingTransaction {
+        let gas_price = tx.max_fee_per_gas.or(tx.gas_price).unwrap_or_default();
+        PendingTransaction {
+            hash: format!("{:?}", tx.hash),
+            from: tx.from.map_or_else(String::new, |a| format!("{:?}", a)),
+            to: tx.to.map_or_else(String::new, |a| format!("{:?}", a)),
+            nonce: tx.nonce.low_u64(),
+            value: crate::wei_to_eth(tx.value),
+            gas_price_gwei: gas_price.low_u128() as f64 / 1e9,
+            queued,
+        }
+    }
+}
+
+/// Transactions of one sender, sorted by nonce
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SenderTransactions {
+    pub address: String,
+    pub pending: Vec<PendingTransaction>,
+    pub queued: Vec<PendingTransaction>,
+    /// Nonces missing before the highest one, the queued transactions wait for them
+    pub nonce_gaps: Vec<NonceGap>,
+}
+
+/// Missing nonces from `start` to `end`, both included
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct NonceGap {
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Ranges of nonces missing from `first` up to the highest of `nonces`.
+/// Built from the known nonces only, a queued nonce can be as high as a sender wants.
+fn nonce_gaps(first: u64, nonces: &BTreeSet<u64>) -> Vec<NonceGap> {
+    let mut gaps = vec![];
+    let mut next = first;
+    for &nonce in nonces.range(first..) {
+        if nonce > next {
+            gaps.push(NonceGap {
+                start: next,
+                end: nonce - 1,
+            });
+        }
+        next = nonce.saturating_add(1);
+    }
+    gaps
+}
+
+/// Amount of pending transactions within a gas price range
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GasPriceBucket {
+    pub label: String,
+    pub count: usize,
+}
+
+/// Counts and gas prices of the mempool, sent to the SSE clients on every refresh
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MempoolSummary {
+    pub source: MempoolSource,
+    pub pending: usize,
+    pub queued: usize,
+    pub senders: usize,
+    pub median_gas_price_gwei: Option<f64>,
+    pub gas_prices: Vec<GasPriceBucket>,
+    /// Unix time of the refresh
+    pub updated_at: i64,
+}
+
+/// The whole mempool view, the senders with the most transactions first
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MempoolSnapshot {
+    pub summary: MempoolSummary,
+    pub senders: Vec<SenderTransactions>,
+}
+
+/// Upper bounds of the gas price buckets, in gwei
+const GAS_PRICE_BOUNDS: [f64; 9] = [1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
+
+/// Senders kept in a snapshot, the counts cover all of them
+const MAX_SENDERS: usize = 100;
+
+/// Senders with queued transactions whose account nonce is looked up per refresh
+const MAX_NONCE_LOOKUPS: usize = 100;
+
+/// How long a transaction seen through the subscription is considered pending
+const SUBSCRIPTION_WINDOW: Duration = Duration::from_secs(600);
+
+/// Transactions kept from the subscription, the oldest are dropped first
+const MAX_SUBSCRIBED: usize = 10_000;
+
+/// Transactions fetched at the same time for the subscribed hashes
+const FETCH_CONCURRENCY: usize = 16;
+
+/// Wait before subscribing again after losing the subscription
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Latest view of the mempool, refreshed in the background when enabled
+#[derive(Clone)]
+pub struct Mempool {
+    pub enabled: bool,
+    snapshot: Arc<RwLock<MempoolSnapshot>>,
+    events: Sender<MempoolSummary>,
+}
+
+impl Mempool {
+    pub fn new(enabled: bool) -> Mempool {
+        let source = if enabled {
+            MempoolSource::Starting
+        } else {
+            MempoolSource::Disabled
+        };
+        Mempool {
+            enabled,
+            snapshot: Arc::new(RwLock::new(snapshot(source, vec![], &HashMap::new()))),
+            events: channel::<MempoolSummary>(1024).0,
+        }
+    }
+
+    pub fn snapshot(&self) -> MempoolSnapshot {
+        self.snapshot.read().unwrap().clone()
+    }
+
+    /// Summaries published on every refresh
+    pub fn subscribe(&self) -> Receiver<MempoolSummary> {
+        self.events.subscribe()
+    }
+
+    /// The transaction with this hash, if it is in the current snapshot
+    pub fn find(&self, hash: &str) -> Option<PendingTransaction> {
+        let hash = hash.to_lowercase();
+        self.snapshot
+            .read()
+            .unwrap()
+            .senders
+            .iter()
+            .flat_map(|s| s.pending.iter().chain(s.queued.iter()))
+            .find(|t| t.hash == hash)
+            .cloned()
+    }
+
+    fn publish(&self, snapshot: MempoolSnapshot) {
+        // Nobody listening is not an error
+        let _ = self.events.send(snapshot.summary.clone());
+        *self.snapshot.write().unwrap() = snapshot;
+    }
+
+    fn set_source(&self, source: MempoolSource) {
+        self.snapshot.write().unwrap().summary.source = source;
+    }
+}
+
+/// Refresh the mempool in the background from launch, when enabled.
+/// `txpool_content` is polled every `interval`, nodes without the txpool api
+/// are followed through `eth_subscribe("newPendingTransactions")` instead.
+pub fn fairing(interval: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Mempool", move |rocket| {
+        Box::pin(async move {
+            let state = (
+                rocket.state::<Mempool>(),
+                rocket.state::<EtherClient>(),
+                rocket.state::<Cache>(),
+            );
+            let (mempool, client, cache) = match state {
+                (Some(mempool), Some(client), Some(cache)) if mempool.enabled => {
+                    (mempool.clone(), client.clone(), cache.clone())
+                }
+                _ => return,
+            };
+            rocket::tokio::spawn(async move {
+                if let Err(e) = poll_txpool(&mempool, &client, &cache, interval).await {
+                    println!("txpool_content unavailable ({}), subscribing instead", e);
+                }
+                follow_subscription(&mempool, &client, interval).await;
+            });
+        })
+    })
+}
+
+/// Poll `txpool_content` until the nodes turn out not to support it
+async fn poll_txpool(
+    mempool: &Mempool,
+    client: &EtherClient,
+    cache: &Cache,
+    interval: Duration,
+) -> Result<(), Error> {
+    let mut supported = false;
+    loop {
+        match client.web3.txpool().content().await {
+            Ok(content) => {
+                supported = true;
+                let mut transactions = vec![];
+                for (queued, txs) in [(false, &content.pending), (true, &content.queued)] {
+                    for tx in txs.values().flat_map(|by_nonce| by_nonce.values()) {
+                        transactions.push(PendingTransaction::new(tx, queued));
+                    }
+                }
+                let account_nonces = account_nonces(client, cache, &transactions).await;
+                mempool.publish(snapshot(
+                    MempoolSource::Txpool,
+                    transactions,
+                    &account_nonces,
+                ));
+            }
+            // Only give up when the method is missing, not on a transient failure
+            Err(web3::Error::Rpc(e)) if !supported => return Err(web3::Error::Rpc(e).into()),
+            Err(e) => println!("Mempool refresh failed: {}", e),
+        }
+        rocket::tokio::time::sleep(interval).await;
+    }
+}
+
+/// Account nonce of the senders with queued transactions, to find their nonce gaps
+async fn account_nonces(
+    client: &EtherClient,
+    cache: &Cache,
+    transactions: &[PendingTransaction],
+) -> HashMap<String, u64> {
+    let senders: BTreeSet<&String> = transactions
+        .iter()
+        .filter(|t| t.queued)
+        .map(|t| &t.from)
+        .collect();
+    let mut nonces = HashMap::new();
+    for sender in senders.into_iter().take(MAX_NONCE_LOOKUPS) {
+        let nonce = parser::parse_request(
+            client,
+            cache,
+            "eth",
+            "nonce",
+            RequestData {
+                data: serde_json::json!({ "address": sender }),
+            },
+        )
+        .await;
+        match nonce.and_then(|n| crate::json_value_hex_to_int(n.data["nonce"].clone())) {
+            Ok(nonce) => {
+                nonces.insert(sender.clone(), nonce as u64);
+            }
+            Err(e) => println!("Nonce of {} unavailable: {}", sender, e),
+        }
+    }
+    nonces
+}
+
+/// Keep the transactions announced by a WebSocket or IPC endpoint for SUBSCRIPTION_WINDOW,
+/// publishing a snapshot every `interval`
+async fn follow_subscription(mempool: &Mempool, client: &EtherClient, interval: Duration) {
+    let push_endpoints: Vec<String> = client
+        .web3
+        .transport()
+        .urls()
+        .into_iter()
+        .filter(|url| node::transport_kind(url) != TransportKind::Http)
+        .collect();
+    if push_endpoints.is_empty() {
+        println!("Mempool unavailable, the nodes have no txpool api and can not push");
+        mempool.set_source(MempoolSource::Unavailable);
+        return;
+    }
+
+    let mut seen: HashMap<H256, (Instant, PendingTransaction)> = HashMap::new();
+    for endpoint in push_endpoints.iter().cycle() {
+        let hashes = match node::subscribe_pending_transactions(endpoint).await {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                println!(
+                    "Pending transactions subscription to {} failed: {}",
+                    node::display_endpoint(endpoint),
+                    node::redact(&e.to_string())
+                );
+                rocket::tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        println!(
+            "Subscribed to pending transactions on {}",
+            node::display_endpoint(endpoint)
+        );
+
+        let eth = client.web3.eth();
+        let mut transactions = hashes
+            .map(|hash| {
+                let eth = eth.clone();
+                async move {
+                    let hash = hash?;
+                    let tx = eth
+                        .transaction(web3::types::TransactionId::Hash(hash))
+                        .await?;
+                    Ok::<_, web3::Error>(tx)
+                }
+            })
+            .buffer_unordered(FETCH_CONCURRENCY);
+        let mut refresh = rocket::tokio::time::interval(interval);
+
+        loop {
+            select! {
+                tx = transactions.next() => match tx {
+                    // Already mined or dropped by the time it was fetched
+                    Some(Ok(Some(tx))) if tx.block_number.is_none() => {
+                        seen.insert(tx.hash, (Instant::now(), PendingTransaction::new(&tx, false)));
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => println!("Fetching a pending transaction failed: {}", e),
+                    None => break,
+                },
+                _ = refresh.tick() => {
+                    seen.retain(|_, (at, _)| at.elapsed() < SUBSCRIPTION_WINDOW);
+                    if seen.len() > MAX_SUBSCRIBED {
+                        let mut by_age: Vec<(Instant, H256)> =
+                            seen.iter().map(|(hash, (at, _))| (*at, *hash)).collect();
+                        by_age.sort();
+                        for (_, hash) in &by_age[..seen.len() - MAX_SUBSCRIBED] {
+                            seen.remove(hash);
+                        }
+                    }
+                    let transactions = seen.values().map(|(_, t)| t.clone()).collect();
+                    mempool.publish(snapshot(
+                        MempoolSource::Subscription,
+                        transactions,
+                        &HashMap::new(),
+                    ));
+                }
+            }
+        }
+        println!(
+            "Pending transactions subscription to {} lost",
+            node::display_endpoint(endpoint)
+        );
+        rocket::tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Group transactions by sender, find the nonce gaps and the gas price distribution.
+/// Gaps start at the account nonce when known, at the lowest nonce in the mempool otherwise.
+fn snapshot(
+    source: MempoolSource,
+    transactions: Vec<PendingTransaction>,
+    account_nonces: &HashMap<String, u64>,
+) -> MempoolSnapshot {
+    let mut gas_prices: Vec<f64> = transactions
+        .iter()
+        .filter(|t| !t.queued)
+        .map(|t| t.gas_price_gwei)
+        .collect();
+    gas_prices.sort_by(|a, b| a.total_cmp(b));
+    let median_gas_price_gwei = gas_prices.get(gas_prices.len() / 2).copied();
+    let mut buckets = vec![0; GAS_PRICE_BOUNDS.len() + 1];
+    for price in &gas_prices {
+        let bucket = GAS_PRICE_BOUNDS.partition_point(|bound| bound <= price);
+        buckets[bucket] += 1;
+    }
+    let gas_prices = buckets
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| GasPriceBucket {
+            label: match i {
+                0 => format!("< {}", GAS_PRICE_BOUNDS[0]),
+                i if i == GAS_PRICE_BOUNDS.len() => format!(">= {}", GAS_PRICE_BOUNDS[i - 1]),
+                i => format!("{} - {}", GAS_PRICE_BOUNDS[i - 1], GAS_PRICE_BOUNDS[i]),
+            },
+            count,
+        })
+        .collect();
+
+    let (pending, queued) =
+        transactions.iter().fold(
+            (0, 0),
+            |(p, q), t| {
+                if t.queued {
+                    (p, q + 1)
+                } else {
+                    (p + 1, q)
+                }
+            },
+        );
+
+    let mut by_sender: BTreeMap<String, Vec<PendingTransaction>> = BTreeMap::new();
+    for t in transactions {
+        by_sender.entry(t.from.clone()).or_default().push(t);
+    }
+    let sender_count = by_sender.len();
+    let mut senders: Vec<SenderTransactions> = by_sender
+        .into_iter()
+        .map(|(address, mut txs)| {
+            txs.sort_by_key(|t| t.nonce);
+            let nonces: BTreeSet<u64> = txs.iter().map(|t| t.nonce).collect();
+            let first = account_nonces
+                .get(&address)
+                .copied()
+                .unwrap_or_else(|| nonces.iter().next().copied().unwrap_or_default());
+            let nonce_gaps = nonce_gaps(first, &nonces);
+            let (queued, pending) = txs.into_iter().partition(|t| t.queued);
+            SenderTransactions {
+                address,
+                pending,
+                queued,
+                nonce_gaps,
+            }
+        })
+        .collect();
+    senders.sort_by_key(|s| std::cmp::Reverse(s.pending.len() + s.queued.len()));
+    senders.truncate(MAX_SENDERS);
+
+    MempoolSnapshot {
+        summary: MempoolSummary {
+            source,
+            pending,
+            queued,
+            senders: sender_count,
+            median_gas_price_gwei,
+            gas_prices,
+            updated_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64),
+        },
+        senders,
+    }
+}
@@ -0,0 +1,382 @@
+use jsonrpc_core as rpc;
+use rocket::serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use web3::futures::future::{join_all, BoxFuture, FutureExt};
+use web3::futures::stream::{BoxStream, StreamExt};
+use web3::transports::{Either, Http, Ipc, WebSocket};
+use web3::{helpers, RequestId, Transport};
+
+/// Transport used for every node connection.
+/// The concrete transport is picked from the endpoint scheme, see `connect`.
+pub type NodeTransport = Either<Http, Either<WebSocket, Ipc>>;
+
+/// Kind of transport an endpoint string resolves to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Http,
+    WebSocket,
+    Ipc,
+}
+
+/// Pick the transport from the endpoint scheme.
+/// `http(s)://` uses HTTP, `ws(s)://` uses WebSocket,
+/// `ipc://` or a plain path (e.g. `/data/geth.ipc`) uses the IPC socket.
+pub fn transport_kind(endpoint: &str) -> TransportKind {
+    if endpoint.starts_with("ws://") || endpoint.starts_with("wss://") {
+        TransportKind::WebSocket
+    } else if endpoint.starts_with("http://") || endpoint.starts_with("https://") {
+        TransportKind::Http
+    } else {
+        TransportKind::Ipc
+    }
+}
+
+/// Open a transport to the given endpoint
+pub async fn connect(endpoint: &str) -> Result<NodeTransport, String> {
+    let transport = match transport_kind(endpoint) {
+        TransportKind::Http => Either::Left(Http::new(endpoint).map_err(|e| format!("{}", e))?),
+        TransportKind::WebSocket => Either::Right(Either::Left(
+            WebSocket::new(endpoint)
+                .await
+                .map_err(|e| format!("{}", e))?,
+        )),
+        TransportKind::Ipc => {
+            let path = endpoint.trim_start_matches("ipc://");
+            Either::Right(Either::Right(
+                Ipc::new(path).await.map_err(|e| format!("{}", e))?,
+            ))
+        }
+    };
+
+    Ok(transport)
+}
+
+/// New block headers pushed by a node
+pub type HeadStream = BoxStream<'static, web3::error::Result<web3::types::BlockHeader>>;
+
+/// Subscribe to `newHeads` on a WebSocket or IPC endpoint.
+/// Fails on HTTP endpoints, they cannot push.
+pub async fn subscribe_new_heads(endpoint: &str) -> web3::error::Result<HeadStream> {
+    let heads = match transport_kind(endpoint) {
+        TransportKind::Http => {
+            return Err(web3::Error::Transport(
+                web3::error::TransportError::Message(format!(
+                    "{} can not push new heads",
+                    endpoint
+                )),
+            ))
+        }
+        TransportKind::WebSocket => web3::Web3::new(WebSocket::new(endpoint).await?)
+            .eth_subscribe()
+            .subscribe_new_heads()
+            .await?
+            .boxed(),
+        TransportKind::Ipc => {
+            web3::Web3::new(Ipc::new(endpoint.trim_start_matches("ipc://")).await?)
+                .eth_subscribe()
+                .subscribe_new_heads()
+                .await?
+                .boxed()
+        }
+    };
+    Ok(heads)
+}
+
+/// Hashes of the transactions entering a node's mempool
+pub type PendingStream = BoxStream<'static, web3::error::Result<web3::types::H256>>;
+
+/// Subscribe to `newPendingTransactions` on a WebSocket or IPC endpoint.
+/// Fails on HTTP endpoints, they cannot push.
+pub async fn subscribe_pending_transactions(endpoint: &str) -> web3::error::Result<PendingStream> {
+    let hashes = match transport_kind(endpoint) {
+        TransportKind::Http => {
+            return Err(web3::Error::Transport(
+                web3::error::TransportError::Message(format!(
+                    "{} can not push pending transactions",
+                    endpoint
+                )),
+            ))
+        }
+        TransportKind::WebSocket => web3::Web3::new(WebSocket::new(endpoint).await?)
+            .eth_subscribe()
+            .subscribe_new_pending_transactions()
+            .await?
+            .boxed(),
+        TransportKind::Ipc => {
+            web3::Web3::new(Ipc::new(endpoint.trim_start_matches("ipc://")).await?)
+                .eth_subscribe()
+                .subscribe_new_pending_transactions()
+                .await?
+                .boxed()
+        }
+    };
+    Ok(hashes)
+}
+
+/// Scheme and host of an endpoint, safe to show: the path, query and credentials
+/// of a provider URL often carry an API key
+pub fn display_endpoint(endpoint: &str) -> String {
+    match endpoint.split_once("://") {
+        Some((scheme, rest)) if scheme != "ipc" => {
+            let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+            let host = authority.rsplit('@').next().unwrap_or_default();
+            format!("{}://{}", scheme, host)
+        }
+        _ => "ipc".to_string(),
+    }
+}
+
+/// An error message with the URLs it mentions cut down by `display_endpoint`,
+/// HTTP errors repeat the whole request URL
+pub fn redact(error: &str) -> String {
+    let mut redacted = String::new();
+    let mut rest = error;
+    while let Some(at) = rest.find("://") {
+        let start = rest[..at]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let end = rest[at..]
+            .find(|c: char| c.is_whitespace() || matches!(c, ')' | '"' | '\'' | '>'))
+            .map_or(rest.len(), |i| at + i);
+        redacted.push_str(&rest[..start]);
+        redacted.push_str(&display_endpoint(&rest[start..end]));
+        rest = &rest[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// Health of a single endpoint, as seen by the last health check
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct EndpointStatus {
+    /// Position in ETH_RPC_ENDPOINT
+    pub index: usize,
+    /// Scheme and host only, see `display_endpoint`
+    pub endpoint: String,
+    pub healthy: bool,
+    pub block_number: Option<u64>,
+    pub syncing: bool,
+    pub lag: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    url: String,
+    /// None until connected. Replaced by the health check when a WebSocket
+    /// or IPC connection dropped or could not be opened.
+    transport: RwLock<Option<NodeTransport>>,
+    status: RwLock<EndpointStatus>,
+}
+
+impl Endpoint {
+    fn transport(&self) -> Option<NodeTransport> {
+        self.transport.read().unwrap().clone()
+    }
+
+    /// Open the connection again, within HEALTH_CHECK_TIMEOUT
+    async fn reconnect(&self) -> Result<(), String> {
+        let transport = rocket::tokio::time::timeout(HEALTH_CHECK_TIMEOUT, connect(&self.url))
+            .await
+            .map_err(|_| "Connection timed out".to_string())??;
+        println!("Connected to {}", display_endpoint(&self.url));
+        *self.transport.write().unwrap() = Some(transport);
+        Ok(())
+    }
+
+    /// Head and sync state of the node, within HEALTH_CHECK_TIMEOUT
+    async fn head(&self) -> Result<(u64, web3::types::SyncState), String> {
+        let transport = self
+            .transport()
+            .ok_or_else(|| "Not connected".to_string())?;
+        let eth = web3::Web3::new(transport).eth();
+        let head = rocket::tokio::time::timeout(HEALTH_CHECK_TIMEOUT, async {
+            let block_number = eth.block_number().await?;
+            let syncing = eth.syncing().await?;
+            Ok::<_, web3::Error>((block_number.as_u64(), syncing))
+        })
+        .await;
+        match head {
+            Ok(Ok(head)) => Ok(head),
+            Ok(Err(e)) => Err(format!("{}", e)),
+            Err(_) => Err("Health check timed out".to_string()),
+        }
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.status.read().unwrap().healthy
+    }
+
+    /// Head and sync state. Unlike HTTP, a dropped WebSocket or IPC connection
+    /// does not come back on its own, it is opened again when the node does not answer.
+    async fn check(&self) -> Result<(u64, web3::types::SyncState), String> {
+        let head = self.head().await;
+        if head.is_err() && transport_kind(&self.url) != TransportKind::Http {
+            self.reconnect().await?;
+            return self.head().await;
+        }
+        head
+    }
+
+    fn mark_failed(&self, error: String) {
+        let mut status = self.status.write().unwrap();
+        status.healthy = false;
+        status.last_error = Some(redact(&error));
+    }
+}
+
+/// Transport spreading requests round-robin over several nodes.
+/// Only healthy, caught-up nodes receive requests; when a node fails
+/// the request is retried on the next one.
+#[derive(Debug, Clone)]
+pub struct Balanced {
+    endpoints: Arc<Vec<Endpoint>>,
+    next: Arc<AtomicUsize>,
+    id: Arc<AtomicUsize>,
+}
+
+impl Balanced {
+    /// Connect to every endpoint. Endpoints that cannot be reached are kept as unhealthy,
+    /// the health check connects them once they come up. Fails when none can be reached.
+    pub async fn connect(urls: &[String]) -> Result<Balanced, String> {
+        let mut endpoints = vec![];
+        for (index, url) in urls.iter().enumerate() {
+            let transport = connect(url).await;
+            if let Err(e) = &transport {
+                println!(
+                    "Unable to connect to {}: {}",
+                    display_endpoint(url),
+                    redact(e)
+                );
+            }
+            endpoints.push(Endpoint {
+                url: url.clone(),
+                status: RwLock::new(EndpointStatus {
+                    index,
+                    endpoint: display_endpoint(url),
+                    healthy: transport.is_ok(),
+                    block_number: None,
+                    syncing: false,
+                    lag: None,
+                    last_error: transport.as_ref().err().map(|e| redact(e)),
+                }),
+                transport: RwLock::new(transport.ok()),
+            });
+        }
+
+        if endpoints.iter().all(|e| e.transport().is_none()) {
+            return Err("No reachable RPC endpoint".to_string());
+        }
+
+        Ok(Balanced {
+            endpoints: Arc::new(endpoints),
+            next: Arc::new(AtomicUsize::new(0)),
+            id: Arc::new(AtomicUsize::new(1)),
+        })
+    }
+
+    /// URLs of the endpoints
+    pub fn urls(&self) -> Vec<String> {
+        self.endpoints.iter().map(|e| e.url.clone()).collect()
+    }
+
+    /// Current health of every endpoint
+    pub fn status(&self) -> Vec<EndpointStatus> {
+        self.endpoints
+            .iter()
+            .map(|e| e.status.read().unwrap().clone())
+            .collect()
+    }
+
+    /// Query eth_blockNumber and eth_syncing on every endpoint at the same time.
+    /// An endpoint is healthy when it answers, is not syncing and is at most
+    /// `max_lag` blocks behind the highest head seen.
+    pub async fn check_health(&self, max_lag: u64) {
+        let heads = join_all(self.endpoints.iter().map(Endpoint::check)).await;
+
+        let best = heads
+            .iter()
+            .filter_map(|h| h.as_ref().ok().map(|(n, _)| *n))
+            .max()
+            .unwrap_or(0);
+
+        for (endpoint, head) in self.endpoints.iter().zip(heads) {
+            let mut status = endpoint.status.write().unwrap();
+            match head {
+                Ok((block_number, syncing)) => {
+                    let lag = best - block_number;
+                    let syncing = syncing != web3::types::SyncState::NotSyncing;
+                    status.block_number = Some(block_number);
+                    status.syncing = syncing;
+                    status.lag = Some(lag);
+                    status.healthy = !syncing && lag <= max_lag;
+                    status.last_error = None;
+                }
+                Err(e) => {
+                    status.healthy = false;
+                    status.last_error = Some(redact(&e));
+                }
+            }
+        }
+    }
+
+    /// Connected endpoints in the order a request should try them:
+    /// healthy ones round-robin first, then the others as a last resort.
+    fn candidates(&self) -> Vec<usize> {
+        let len = self.endpoints.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let order: Vec<usize> = (0..len)
+            .map(|i| (start + i) % len)
+            .filter(|i| self.endpoints[*i].transport().is_some())
+            .collect();
+        let (mut healthy, unhealthy): (Vec<usize>, Vec<usize>) = order
+            .into_iter()
+            .partition(|i| self.endpoints[*i].is_healthy());
+        healthy.extend(unhealthy);
+        healthy
+    }
+}
+
+const HEALTH_CHECK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+impl Transport for Balanced {
+    type Out = BoxFuture<'static, web3::error::Result<rpc::Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<rpc::Value>) -> (RequestId, rpc::Call) {
+        let id = self.id.fetch_add(1, Ordering::AcqRel);
+        (id, helpers::build_request(id, method, params))
+    }
+
+    fn send(&self, id: RequestId, request: rpc::Call) -> Self::Out {
+        let balanced = self.clone();
+        async move {
+            let mut last_error = web3::Error::Unreachable;
+            for i in balanced.candidates() {
+                let endpoint = &balanced.endpoints[i];
+                let transport = match endpoint.transport() {
+                    Some(transport) => transport,
+                    None => continue,
+                };
+                match transport.send(id, request.clone()).await {
+                    // The node answered, even with an RPC error there is no point in asking another one
+                    Ok(value) => return Ok(value),
+                    Err(web3::Error::Rpc(e)) => return Err(web3::Error::Rpc(e)),
+                    Err(e) => {
+                        println!(
+                            "RPC endpoint failed, trying the next one: {}",
+                            redact(&e.to_string())
+                        );
+                        endpoint.mark_failed(format!("{}", e));
+                        last_error = e;
+                    }
+                }
+            }
+            Err(last_error)
+        }
+        .boxed()
+    }
+}
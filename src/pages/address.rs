@@ -1,6 +1,10 @@
-use crate::{parser, RequestData};
+use crate::cache::CacheOp;
+use crate::error::Error;
+use crate::login::User;
+use crate::{parser, EtherClient, RequestData, State};
 use rocket::serde::{Deserialize, Serialize};
 use rocket_dyn_templates::{context, Template};
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -11,213 +15,507 @@ pub struct SimpleAddress {
     pub transactions: Vec<SimpleTransaction>,
 }
 
-#[get("/address/<address_hex>")]
-pub async fn address(address_hex: &str) -> Template {
+/// Transactions per page on the address page
+const ADDRESS_PAGE_SIZE: usize = 25;
+
+// Rocket hands every query parameter and guard to the handler
+#[allow(clippy::too_many_arguments)]
+#[get("/address/<address_hex>?<page>&<sort>&<from_block>&<to_block>")]
+pub async fn address(
+    address_hex: &str,
+    page: Option<usize>,
+    sort: Option<String>,
+    from_block: Option<i64>,
+    to_block: Option<i64>,
+    user: Option<User>,
+    cache: &crate::State<crate::Cache>,
+    client: &State<EtherClient>,
+) -> Result<Template, Error> {
     let a = crate::clean(address_hex.to_string());
     let address = &parser::parse_request(
+        client,
+        cache,
         "eth",
         "balance",
         RequestData {
             data: serde_json::json!({ "address": a }),
         },
     )
-    .await;
+    .await?;
 
     let result = address.data.clone();
-    Template::render("address", context! { address: result })
+
+    let page = page.unwrap_or(0);
+    let ascending = sort.as_deref() == Some("asc");
+    let resolved = result["address"]
+        .as_str()
+        .unwrap_or_default()
+        .to_lowercase();
+    let (transactions, total) =
+        address_transactions(cache, &resolved, from_block, to_block, page, ascending).await?;
+    let pages = total.div_ceil(ADDRESS_PAGE_SIZE);
+    let user_data = crate::userdata::for_page(cache, user.as_ref()).await;
+    let names = crate::ens::names_for_page(
+        client,
+        cache,
+        crate::ens::addresses_in(&serde_json::json!([result, transactions])),
+    )
+    .await;
+    // The name typed in the URL, otherwise the primary name of the address
+    let ens_name = if a.contains('.') {
+        Some(a.to_lowercase())
+    } else {
+        names.get(&resolved).cloned()
+    };
+    let ens = match ens_name {
+        Some(name) => crate::ens::profile(client, cache, &name)
+            .await
+            .map_err(|e| println!("ENS profile of {} unavailable: {}", name, e))
+            .ok(),
+        None => None,
+    };
+
+    Ok(Template::render(
+        "address",
+        context! {
+            address: result,
+            transactions: transactions,
+            total: total,
+            page: page,
+            pages: pages,
+            sort: if ascending { "asc" } else { "desc" },
+            from_block: from_block,
+            to_block: to_block,
+            cache_enabled: cache.enabled,
+            signed_in: user.is_some(),
+            watched: user_data.is_watched(&resolved),
+            labels: user_data.labels,
+            names: names,
+            ens: ens,
+        },
+    ))
 }
 
 use crate::pages::block::retrieve_transactions;
 
 use super::block::SimpleTransaction;
 
-pub async fn get_balance(address: &str) -> String {
+pub async fn get_balance(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    address: &str,
+) -> Result<String, Error> {
     let address = &parser::parse_request(
+        client,
+        cache,
         "eth",
         "balance",
         RequestData {
             data: serde_json::json!({ "address": address }),
         },
     )
-    .await;
+    .await?;
+
+    Ok(address.data.clone()["balance"].to_string())
+}
+
+/// Transaction index slots per block in the address index score,
+/// `score = block_number * TX_INDEX_SLOTS + transaction_index`.
+/// Scores stay exact in an f64 up to block 9e10.
+const TX_INDEX_SLOTS: i64 = 100_000;
+
+/// A transaction as stored in the per-tx hash `txrecord_<hash>`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(crate = "rocket::serde")]
+pub struct IndexedTransaction {
+    pub hash: String,
+    pub from: String,
+    pub to: String,
+    pub value: String,
+    pub block_hash: String,
+    pub block_number: i64,
+    pub transaction_index: i64,
+    pub timestamp: i64,
+}
+
+impl IndexedTransaction {
+    fn key(hash: &str) -> String {
+        format!("txrecord_{}", hash)
+    }
+
+    fn score(&self) -> f64 {
+        (self.block_number * TX_INDEX_SLOTS + self.transaction_index) as f64
+    }
+
+    fn fields(&self) -> Vec<(String, String)> {
+        vec![
+            ("hash".to_string(), self.hash.clone()),
+            ("from".to_string(), self.from.clone()),
+            ("to".to_string(), self.to.clone()),
+            ("value".to_string(), self.value.clone()),
+            ("blockHash".to_string(), self.block_hash.clone()),
+            ("blockNumber".to_string(), self.block_number.to_string()),
+            (
+                "transactionIndex".to_string(),
+                self.transaction_index.to_string(),
+            ),
+            ("timestamp".to_string(), self.timestamp.to_string()),
+        ]
+    }
+
+    fn from_fields(fields: &HashMap<String, String>) -> Result<IndexedTransaction, Error> {
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .cloned()
+                .ok_or_else(|| Error::Parse(format!("Indexed transaction without {}", name)))
+        };
+        let number = |name: &str| {
+            field(name)?
+                .parse::<i64>()
+                .map_err(|_| Error::Parse(format!("Invalid {} in indexed transaction", name)))
+        };
+        Ok(IndexedTransaction {
+            hash: field("hash")?,
+            from: field("from")?,
+            to: field("to")?,
+            value: field("value")?,
+            block_hash: field("blockHash")?,
+            block_number: number("blockNumber")?,
+            transaction_index: number("transactionIndex")?,
+            timestamp: number("timestamp")?,
+        })
+    }
+
+    /// Queue the per-tx record and the scored set entries of both addresses.
+    /// Every write is idempotent, indexing the same transaction twice is harmless.
+    fn index(&self, ops: &mut Vec<CacheOp>) {
+        let score = self.score();
+        ops.push(CacheOp::SetFields {
+            key: IndexedTransaction::key(&self.hash),
+            fields: self.fields(),
+        });
+        ops.push(CacheOp::AddScored {
+            key: address_index_key(&self.from),
+            member: self.hash.clone(),
+            score,
+        });
+        // Contract creations have no recipient
+        if self.to != self.from && self.to != "null" {
+            ops.push(CacheOp::AddScored {
+                key: address_index_key(&self.to),
+                member: self.hash.clone(),
+                score,
+            });
+        }
+    }
+}
+
+/// Scored set of the transaction hashes of an address, scored by position in the chain
+fn address_index_key(address: &str) -> String {
+    format!("addressindex_{}", address)
+}
+
+/// An indexed transaction seen from one address
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(crate = "rocket::serde")]
+pub struct AddressTransaction {
+    pub hash: String,
+    /// "in", "out" or "self"
+    pub direction: String,
+    pub counterparty: String,
+    pub value: String,
+    pub block_number: i64,
+    pub timestamp: i64,
+}
 
-    let balance = address.data.clone()["balance"].to_string();
-    return balance;
+impl AddressTransaction {
+    pub fn new(address: &str, t: IndexedTransaction) -> AddressTransaction {
+        let (direction, counterparty) = if t.from == address && t.to == address {
+            ("self", t.to)
+        } else if t.from == address {
+            ("out", t.to)
+        } else {
+            ("in", t.from)
+        };
+        // value is the decimal wei string from parser::response_to_human_readable
+        let value = web3::types::U256::from_dec_str(&t.value)
+            .map(crate::wei_to_eth)
+            .unwrap_or(t.value);
+        AddressTransaction {
+            hash: t.hash,
+            direction: direction.to_string(),
+            counterparty,
+            value,
+            block_number: t.block_number,
+            timestamp: t.timestamp,
+        }
+    }
+}
+
+/// Transactions indexed for an address between two blocks (inclusive),
+/// newest first unless `ascending`.
+/// Returns the requested page and the total amount of matching transactions.
+pub async fn address_transactions(
+    cache: &crate::Cache,
+    address: &str,
+    from_block: Option<i64>,
+    to_block: Option<i64>,
+    page: usize,
+    ascending: bool,
+) -> Result<(Vec<AddressTransaction>, usize), Error> {
+    let (transactions, total) = indexed_transactions(
+        cache,
+        address,
+        from_block,
+        to_block,
+        page * ADDRESS_PAGE_SIZE,
+        ADDRESS_PAGE_SIZE,
+        ascending,
+    )
+    .await?;
+    let transactions = transactions
+        .into_iter()
+        .map(|t| AddressTransaction::new(address, t))
+        .collect();
+    Ok((transactions, total))
 }
 
-/// This function is used to retrieve the transactions of an address.
-/// It is used in the block page and the address page.
-/// It is also used in the crawler to retrieve the transactions of a block.
-/// note: could be much better implemented.
-pub async fn cache_addresses_transactions_from_block(block_number: i64) {
-    // This whole cache should be coming from the Rocket handler, not here.
-    let redis_cache = crate::Cache {
-        enabled: *crate::REDIS_CACHE.lock().unwrap(),
-
-        // Temporary redefining it here, should be moved around coming from the Rocket handler
-        redis_client: Some(redis::Client::open("redis://localhost:6379").unwrap()),
+/// Records of the transactions indexed for an address between two blocks (inclusive),
+/// skipping `offset` of them and returning at most `count`.
+/// Also returns the total amount of matching transactions.
+pub async fn indexed_transactions(
+    cache: &crate::Cache,
+    address: &str,
+    from_block: Option<i64>,
+    to_block: Option<i64>,
+    offset: usize,
+    count: usize,
+    ascending: bool,
+) -> Result<(Vec<IndexedTransaction>, usize), Error> {
+    if !cache.enabled {
+        return Ok((vec![], 0));
+    }
+
+    let key = address_index_key(address);
+    // Score of a slot of a block, blocks too high for a score are after every indexed one
+    let score = |block: i64, slot: i64| {
+        if block < 0 {
+            return Err(Error::Parse(format!("Invalid block number {}", block)));
+        }
+        Ok(block
+            .checked_mul(TX_INDEX_SLOTS)
+            .and_then(|score| score.checked_add(slot))
+            .map_or(f64::INFINITY, |score| score as f64))
     };
+    let min = from_block.map_or(Ok(f64::NEG_INFINITY), |b| score(b, 0))?;
+    let max = to_block.map_or(Ok(f64::INFINITY), |b| score(b, TX_INDEX_SLOTS - 1))?;
+
+    let backend = cache.backend()?;
+    let total = backend.count_by_score(&key, min, max).await?;
+    let hashes = backend
+        .range_by_score(&key, min, max, ascending, offset, count)
+        .await?;
 
+    let keys: Vec<String> = hashes.iter().map(|h| IndexedTransaction::key(h)).collect();
+    let mut transactions = vec![];
+    for fields in backend.fields(&keys).await? {
+        transactions.push(IndexedTransaction::from_fields(&fields)?);
+    }
+    Ok((transactions, total))
+}
+
+/// This function is used to index the transactions of a block by address.
+/// It is used by the polling loop for new blocks and by the crawler for past ones.
+/// Each transaction is stored in a field map, `txrecord_<hash>`, and added to the scored set
+/// `addressindex_<address>` of its sender and recipient. All the writes of a block
+/// go through a single batch together with the `indexedblock_<number>` marker.
+pub async fn cache_addresses_transactions_from_block(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    block_number: i64,
+) -> Result<(), Error> {
     // if indexed block is already cached, don't do anything
-    if redis_cache.enabled {
-        if crate::rcache::check_cache(
-            redis_cache.redis_client.clone().unwrap(),
-            &block_number.to_string(),
-        )
-        .unwrap()
-        {
-            println!("BLOCK {} ALREADY CACHED", block_number);
-            return;
-        }
+    let indexed_block_key = format!("indexedblock_{}", block_number);
+    if cache.backend()?.exists(&indexed_block_key).await? {
+        println!("BLOCK {} ALREADY CACHED", block_number);
+        return Ok(());
     }
 
     let block = &parser::parse_request(
+        client,
+        cache,
         "eth",
         "block",
         RequestData {
             data: serde_json::json!({ "blockNumber": block_number }),
         },
     )
-    .await;
+    .await?;
     let result = block.data["block"].clone();
 
     let b_h = result["hash"].to_string();
+    let timestamp = crate::json_value_hex_to_int(result["timestamp"].clone())? as i64;
     let transactions =
-        retrieve_transactions(result["transactions"].clone(), &redis_cache, b_h.clone()).await;
-
-    for t in transactions {
-        let t_h = t.hash;
-        let t_f = t.from;
-        let t_t = t.to;
-        let t_a = t.value;
-
-        // if transaction is already indexed
-        if crate::rcache::check_cache(
-            redis_cache.redis_client.clone().unwrap(),
-            &format!("indexed_{}", t_h),
-        )
-        .unwrap()
-        {
-            println!("TX {} ALREADY CACHED", t_h);
-            continue;
-        }
-        // if address exists in redis
-
-        if crate::rcache::check_cache(redis_cache.redis_client.clone().unwrap(), &t_f).unwrap() {
-            // deserialize string into SimpleAddress
-            let cached_address = crate::rcache::get(
-                redis_cache.redis_client.clone().unwrap(),
-                &format!("address_{}", t_f),
-            )
-            .unwrap();
-            let mut cached_address: SimpleAddress = serde_json::from_str(&cached_address).unwrap();
-
-            cached_address.transactions.push(SimpleTransaction {
-                block_hash: b_h.clone(),
-                hash: t_h.clone(),
-                from: t_f.clone(),
-                to: t_t.clone(),
-                value: t_a.clone(),
-            });
+        retrieve_transactions(client, result["transactions"].clone(), cache, b_h.clone()).await?;
 
-            // serialize SimpleAddress back to string
-            let cached_address = serde_json::to_string(&cached_address).unwrap();
+    let mut ops = vec![];
+    let mut indexed = IndexedBlock {
+        hash: crate::clean(b_h),
+        transactions: vec![],
+    };
+    // retrieve_transactions keeps the order of the block
+    for (transaction_index, t) in transactions.into_iter().enumerate() {
+        let t = IndexedTransaction {
+            hash: t.hash,
+            from: t.from,
+            to: t.to,
+            value: t.value,
+            block_hash: t.block_hash,
+            block_number,
+            transaction_index: transaction_index as i64,
+            timestamp,
+        };
+        t.index(&mut ops);
+        indexed.transactions.push(t);
+    }
+    ops.push(CacheOp::Set {
+        key: indexed_block_key,
+        value: serde_json::to_string(&indexed)?,
+        ttl: None,
+    });
+    cache.backend()?.batch(ops).await?;
 
-            println!("SAVING TX OUTBOUND {} to ADDRESS {}", t_h, t_f);
+    Ok(())
+}
 
-            // write it back on redis
-            let _ = crate::rcache::set(
-                redis_cache.redis_client.clone().unwrap(),
-                &format!("address_{}", t_f.clone()),
-                &cached_address,
-            );
-        } else {
-            // create new SimpleAddress
-            let new_address = SimpleAddress {
-                address: t_f.clone(),
-                balance: "0".to_string(), // no cache for balance
-                transactions: vec![SimpleTransaction {
-                    block_hash: b_h.clone(),
-                    hash: t_h.clone(),
-                    from: t_f.clone(),
-                    to: t_t.clone(),
-                    value: t_a.clone(),
-                }],
-            };
+/// What was indexed for a block, kept under `indexedblock_<number>` to undo it after a reorg
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct IndexedBlock {
+    hash: String,
+    transactions: Vec<IndexedTransaction>,
+}
 
-            // serialize SimpleAddress back to string
-            let new_address = serde_json::to_string(&new_address).unwrap();
+/// Remove the transactions indexed for a block that is no longer canonical,
+/// so that the block at this height gets indexed again.
+/// Cached transaction pages of these transactions are dropped as well.
+pub async fn rollback_indexed_block(cache: &crate::Cache, block_number: i64) -> Result<(), Error> {
+    let backend = cache.backend()?;
+    let indexed_block_key = format!("indexedblock_{}", block_number);
+    // Blocks indexed by earlier versions only have a "1" marker, nothing to undo
+    let indexed: Option<IndexedBlock> = backend
+        .get(&indexed_block_key)
+        .await?
+        .and_then(|v| serde_json::from_str(&v).ok());
 
-            // write it back on redis
-            let _ = crate::rcache::set(
-                redis_cache.redis_client.clone().unwrap(),
-                &format!("address_{}", t_f.clone()),
-                &new_address,
-            );
+    let mut ops = vec![];
+    for t in indexed.map(|i| i.transactions).into_iter().flatten() {
+        for address in [&t.from, &t.to] {
+            ops.push(CacheOp::RemoveScored {
+                key: address_index_key(address),
+                member: t.hash.clone(),
+            });
         }
+        ops.push(CacheOp::Delete {
+            key: IndexedTransaction::key(&t.hash),
+        });
+        ops.push(CacheOp::Delete { key: t.hash });
+    }
+    ops.push(CacheOp::Delete {
+        key: indexed_block_key,
+    });
+    backend.batch(ops).await
+}
 
-        // if address exists in redis
-        if crate::rcache::check_cache(redis_cache.redis_client.clone().unwrap(), &t_t).unwrap() {
-            // deserialize string into SimpleAddress
-            let cached_address = crate::rcache::get(
-                redis_cache.redis_client.clone().unwrap(),
-                &format!("address_{}", t_t),
-            )
-            .unwrap();
-            let mut cached_address: SimpleAddress = serde_json::from_str(&cached_address).unwrap();
-
-            cached_address.transactions.push(SimpleTransaction {
-                block_hash: b_h.clone(),
-                hash: t_h.clone(),
-                from: t_f.clone(),
-                to: t_t.clone(),
-                value: t_a.clone(),
-            });
+/// Convert the JSON `address_<address>` values written by earlier versions
+/// into the per-tx records and per-address scored sets.
+/// The old values have no block number or position, those are fetched from the node.
+/// Returns the amount of converted keys.
+pub async fn migrate_address_index(
+    client: &EtherClient,
+    cache: &crate::Cache,
+) -> Result<usize, Error> {
+    let backend = cache.backend()?;
+    let keys = backend.keys("address_").await?;
+    let mut timestamps: HashMap<i64, i64> = HashMap::new();
 
-            // serialize SimpleAddress back to string
-            let cached_address = serde_json::to_string(&cached_address).unwrap();
+    for key in &keys {
+        println!("Migrating {}", key);
+        let old: SimpleAddress = match backend.get(key).await? {
+            Some(old) => serde_json::from_str(&old)?,
+            None => continue,
+        };
 
-            println!("SAVING TX INBOUD {} to ADDRESS {}", t_h, t_t);
+        let mut ops = vec![];
+        for t in old.transactions {
+            let transaction = match parser::parse_request(
+                client,
+                cache,
+                "eth",
+                "transaction",
+                RequestData {
+                    data: serde_json::json!({ "tx": t.hash }),
+                },
+            )
+            .await
+            {
+                Ok(transaction) => transaction.data["transaction"].clone(),
+                // Dropped after a reorg, there is nothing left to index
+                Err(Error::NotFound(_)) => {
+                    println!("Skipping {}, unknown to the node", t.hash);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
 
-            // write it back on redis
-            let _ = crate::rcache::set(
-                redis_cache.redis_client.clone().unwrap(),
-                &format!("address_{}", t_t.clone()),
-                &cached_address,
-            );
-        } else {
-            // create new SimpleAddress
-            let new_address = SimpleAddress {
-                address: t_t.clone(),
-                balance: "0".to_string(), // no cache for balance
-                transactions: vec![SimpleTransaction {
-                    block_hash: b_h.clone(),
-                    hash: t_h.clone(),
-                    from: t_f.clone(),
-                    to: t_t.clone(),
-                    value: t_a.clone(),
-                }],
+            // Still pending after a reorg, the indexer will pick it up once mined
+            let block_number = match crate::clean(transaction["blockNumber"].to_string()).parse() {
+                Ok(block_number) => block_number,
+                Err(_) => continue,
             };
+            let transaction_index = crate::clean(transaction["transactionIndex"].to_string())
+                .parse()
+                .map_err(|_| Error::Parse(format!("Invalid transaction index for {}", t.hash)))?;
 
-            // serialize SimpleAddress back to string
-            let new_address = serde_json::to_string(&new_address).unwrap();
+            let timestamp = match timestamps.get(&block_number) {
+                Some(timestamp) => *timestamp,
+                None => {
+                    let block = parser::parse_request(
+                        client,
+                        cache,
+                        "eth",
+                        "block",
+                        RequestData {
+                            data: serde_json::json!({ "blockNumber": block_number }),
+                        },
+                    )
+                    .await?;
+                    let timestamp =
+                        crate::json_value_hex_to_int(block.data["block"]["timestamp"].clone())?
+                            as i64;
+                    timestamps.insert(block_number, timestamp);
+                    timestamp
+                }
+            };
 
-            // write it back on redis
-            let _ = crate::rcache::set(
-                redis_cache.redis_client.clone().unwrap(),
-                &format!("address_{}", t_t.clone()),
-                &new_address,
-            );
+            IndexedTransaction {
+                hash: t.hash,
+                from: t.from,
+                to: t.to,
+                value: t.value,
+                block_hash: crate::clean(transaction["blockHash"].to_string()),
+                block_number,
+                transaction_index,
+                timestamp,
+            }
+            .index(&mut ops);
         }
-        let _ = crate::rcache::set(
-            redis_cache.redis_client.clone().unwrap(),
-            &format!("indexedtx_{}", t_h.clone()),
-            &"1".to_string(),
-        );
+        ops.push(CacheOp::Delete { key: key.clone() });
+        backend.batch(ops).await?;
     }
-    let _ = crate::rcache::set(
-        redis_cache.redis_client.clone().unwrap(),
-        &format!("indexedblock_{}", b_h.clone()),
-        &"1".to_string(),
-    );
+
+    Ok(keys.len())
 }
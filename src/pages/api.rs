@@ -0,0 +1,701 @@
+use crate::decoder::AbiRegistry;
+use crate::error::Error;
+use crate::pages::address::indexed_transactions;
+use crate::{parser, EtherClient, RequestData, State};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use rocket::serde::{json::Json, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use web3::types::{H160, H256, U256};
+use web3::Transport;
+
+/// Etherscan's response envelope, `status` is "1" on success and "0" otherwise
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Envelope {
+    pub status: &'static str,
+    pub message: String,
+    pub result: serde_json::Value,
+}
+
+impl Envelope {
+    fn ok(result: serde_json::Value) -> Envelope {
+        Envelope {
+            status: "1",
+            message: "OK".to_string(),
+            result,
+        }
+    }
+
+    /// A list, empty lists are reported with `status` "0" like Etherscan does
+    fn list(results: Vec<serde_json::Value>, empty: &str) -> Envelope {
+        if results.is_empty() {
+            Envelope {
+                status: "0",
+                message: empty.to_string(),
+                result: serde_json::json!([]),
+            }
+        } else {
+            Envelope::ok(serde_json::Value::from(results))
+        }
+    }
+
+    fn error(e: Error) -> Envelope {
+        let message = match e {
+            Error::Parse(m) | Error::NotFound(m) | Error::Conflict(m) => m,
+            e => e.to_string(),
+        };
+        Envelope {
+            status: "0",
+            message: "NOTOK".to_string(),
+            result: serde_json::Value::from(format!("Error! {}", message)),
+        }
+    }
+}
+
+/// Query parameters of an api call
+type Params = HashMap<String, String>;
+
+/// Deepest record a list action pages to, as on Etherscan
+const MAX_RECORDS: u64 = 10_000;
+
+/// Most transactions of a `txlist` page, each one costs two node calls
+const MAX_TXLIST_PAGE_SIZE: u64 = 100;
+
+/// Most logs returned by `getLogs`
+const MAX_LOGS: u64 = 1_000;
+
+/// Block numbers from this one on are out of the address index range
+const MAX_INDEXED_BLOCK: u64 = 90_000_000_000;
+
+/// Most addresses of a `balancemulti` call
+const MAX_BALANCES: usize = 20;
+
+/// Transactions and receipts fetched from the node at the same time
+const FETCH_CONCURRENCY: usize = 16;
+
+/// Blocks measured to estimate the block time of `getblockcountdown`
+const BLOCK_TIME_SAMPLE: i128 = 100;
+
+/// Etherscan-compatible api, `/api?module=account&action=txlist&address=...`.
+/// Errors are reported in the envelope with a 200 status, as Etherscan clients expect.
+/// The `apikey` parameter is accepted and ignored.
+#[get("/api?<params..>")]
+pub async fn api(
+    params: Params,
+    cache: &State<crate::Cache>,
+    client: &State<EtherClient>,
+    abi_registry: &State<AbiRegistry>,
+) -> Json<serde_json::Value> {
+    let module = params.get("module").map(String::as_str).unwrap_or_default();
+    let action = params.get("action").map(String::as_str).unwrap_or_default();
+    if module == "proxy" {
+        return Json(proxy(action, &params, client).await);
+    }
+    let response = match module {
+        "account" => account(action, &params, client, cache, abi_registry).await,
+        "block" => block(action, &params, client, cache).await,
+        "transaction" => transaction(action, &params, client, cache).await,
+        "logs" => logs(action, &params, client, cache).await,
+        "stats" => stats(action, &params, client, cache).await,
+        _ => Err(Error::Parse("Missing Or invalid Module name".to_string())),
+    };
+    let envelope = response.unwrap_or_else(Envelope::error);
+    Json(serde_json::to_value(envelope).unwrap_or_default())
+}
+
+fn invalid_action() -> Error {
+    Error::Parse("Missing Or invalid Action name".to_string())
+}
+
+fn required<'a>(params: &'a Params, name: &str) -> Result<&'a str, Error> {
+    params
+        .get(name)
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| Error::Parse(format!("Missing {}", name)))
+}
+
+fn number(params: &Params, name: &str, default: u64) -> Result<u64, Error> {
+    match params.get(name).map(|v| v.trim()).filter(|v| !v.is_empty()) {
+        None => Ok(default),
+        Some(v) => v
+            .parse()
+            .map_err(|_| Error::Parse(format!("Invalid {} {}", name, v))),
+    }
+}
+
+fn address(value: &str) -> Result<H160, Error> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::Parse("Invalid address format".to_string()))
+}
+
+fn hash(value: &str) -> Result<H256, Error> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| Error::Parse("Invalid transaction hash".to_string()))
+}
+
+/// A block tag of the proxy module: latest, earliest, pending or a hex block number
+fn tag(params: &Params) -> Result<String, Error> {
+    let tag = params.get("tag").map(|t| t.trim()).unwrap_or("latest");
+    let valid = matches!(tag, "latest" | "earliest" | "pending")
+        || tag
+            .strip_prefix("0x")
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_hexdigit()));
+    if !valid {
+        return Err(Error::Parse(format!("Invalid tag {}", tag)));
+    }
+    Ok(tag.to_string())
+}
+
+/// `page` (from 1) and `offset` (page size, `size` by default and at most `max_size`)
+/// as the index of the first record and the page size
+fn page(params: &Params, size: u64, max_size: u64, max: u64) -> Result<(usize, usize), Error> {
+    let page = number(params, "page", 1)?.max(1);
+    let offset = number(params, "offset", size)?;
+    if offset > max_size {
+        return Err(Error::Parse(format!(
+            "Offset size must be less than or equal to {}",
+            max_size
+        )));
+    }
+    if offset == 0 || page.saturating_mul(offset) > max {
+        return Err(Error::Parse(format!(
+            "Result window is too large, PageNo x Offset size must be less than or equal to {}",
+            max
+        )));
+    }
+    Ok((((page - 1) * offset) as usize, offset as usize))
+}
+
+async fn request(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    method: &str,
+    data: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    Ok(
+        parser::parse_request(client, cache, "eth", method, RequestData { data })
+            .await?
+            .data,
+    )
+}
+
+async fn head(client: &EtherClient, cache: &crate::Cache) -> Result<i128, Error> {
+    let head = request(client, cache, "blockNumber", serde_json::json!({})).await?;
+    crate::json_value_hex_to_int(head["blockNumber"].clone())
+}
+
+async fn block_timestamp(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    number: i128,
+) -> Result<i128, Error> {
+    let block = request(
+        client,
+        cache,
+        "block",
+        serde_json::json!({ "blockNumber": number as u64 }),
+    )
+    .await?;
+    crate::json_value_hex_to_int(block["block"]["timestamp"].clone())
+}
+
+/// A decimal string from a hex quantity, or from a decimal one made by the parser
+fn decimal(v: &serde_json::Value) -> String {
+    match v.as_str() {
+        Some(s) if s.starts_with("0x") => crate::json_value_hex_to_int(v.clone())
+            .map(|n| n.to_string())
+            .unwrap_or_default(),
+        Some(s) => s.to_string(),
+        None => String::new(),
+    }
+}
+
+/// A uint256 returned by `eth_call`, as a decimal string
+async fn call_uint(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    to: H160,
+    data: String,
+) -> Result<String, Error> {
+    let result = request(
+        client,
+        cache,
+        "call",
+        serde_json::json!({ "to": format!("{:?}", to), "data": data }),
+    )
+    .await?;
+    let bytes = crate::decoder::decode_hex(result["result"].as_str().unwrap_or_default())
+        .filter(|b| b.len() >= 32)
+        .ok_or_else(|| Error::Parse(format!("{:?} is not a token contract", to)))?;
+    Ok(U256::from_big_endian(&bytes[..32]).to_string())
+}
+
+async fn balance(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    address: H160,
+) -> Result<String, Error> {
+    let result = request(
+        client,
+        cache,
+        "balance",
+        serde_json::json!({ "address": format!("{:?}", address) }),
+    )
+    .await?;
+    let balance: U256 = serde_json::from_value(result["balance"].clone())?;
+    Ok(balance.to_string())
+}
+
+async fn account(
+    action: &str,
+    params: &Params,
+    client: &EtherClient,
+    cache: &crate::Cache,
+    abi_registry: &AbiRegistry,
+) -> Result<Envelope, Error> {
+    match action {
+        "balance" => {
+            let address = address(required(params, "address")?)?;
+            Ok(Envelope::ok(balance(client, cache, address).await?.into()))
+        }
+        "balancemulti" => {
+            let addresses = required(params, "address")?
+                .split(',')
+                .map(address)
+                .collect::<Result<Vec<H160>, Error>>()?;
+            if addresses.len() > MAX_BALANCES {
+                return Err(Error::Parse(format!("At most {} addresses", MAX_BALANCES)));
+            }
+            let mut balances = vec![];
+            for address in addresses {
+                balances.push(serde_json::json!({
+                    "account": format!("{:?}", address),
+                    "balance": balance(client, cache, address).await?,
+                }));
+            }
+            Ok(Envelope::ok(balances.into()))
+        }
+        "txlist" => txlist(params, client, cache, abi_registry).await,
+        "tokenbalance" => {
+            let contract = address(required(params, "contractaddress")?)?;
+            let holder = address(required(params, "address")?)?;
+            // balanceOf(address)
+            let data = format!("0x70a08231{:0>64}", hex::encode(holder.as_bytes()));
+            Ok(Envelope::ok(
+                call_uint(client, cache, contract, data).await?.into(),
+            ))
+        }
+        _ => Err(invalid_action()),
+    }
+}
+
+/// Transactions of an address from the address index, with their receipts
+async fn txlist(
+    params: &Params,
+    client: &EtherClient,
+    cache: &crate::Cache,
+    abi_registry: &AbiRegistry,
+) -> Result<Envelope, Error> {
+    if !cache.enabled {
+        return Err(Error::Conflict(
+            "Transaction lists need the cache (--cache) and the indexer".to_string(),
+        ));
+    }
+    let address = format!("{:?}", address(required(params, "address")?)?);
+    // Clients pass 99999999 or more for "up to the head"
+    let block = |name: &str| -> Result<Option<i64>, Error> {
+        number(params, name, u64::MAX).map(|n| (n < MAX_INDEXED_BLOCK).then_some(n as i64))
+    };
+    let start_block = block("startblock")?;
+    let end_block = block("endblock")?;
+    // Etherscan returns the whole list without an offset, ask for pages instead of truncating it
+    if required(params, "offset").is_err() {
+        return Err(Error::Parse(format!(
+            "Missing offset, txlist is paginated with page and offset (at most {})",
+            MAX_TXLIST_PAGE_SIZE
+        )));
+    }
+    let (offset, count) = page(
+        params,
+        MAX_TXLIST_PAGE_SIZE,
+        MAX_TXLIST_PAGE_SIZE,
+        MAX_RECORDS,
+    )?;
+    let descending = params.get("sort").map(String::as_str) == Some("desc");
+
+    let (transactions, _) = indexed_transactions(
+        cache,
+        &address,
+        start_block,
+        end_block,
+        offset,
+        count,
+        !descending,
+    )
+    .await?;
+    let head = head(client, cache).await?;
+
+    // Fetched out of order, then put back in the order of the index
+    let transactions = transactions.into_iter().enumerate();
+    let mut results: Vec<(usize, serde_json::Value)> = stream::iter(transactions)
+        .map(|(position, indexed)| async move {
+            let tx = serde_json::json!({ "tx": indexed.hash });
+            let (transaction, receipt) = futures::join!(
+                request(client, cache, "transaction", tx.clone()),
+                request(client, cache, "receipt", tx),
+            );
+            let transaction = &transaction?["transaction"];
+            let receipt = &receipt?["receipt"];
+            let input = transaction["input"].as_str().unwrap_or("0x");
+            let decoded = abi_registry.decode_input(input);
+            let status = decimal(&receipt["status"]);
+            // Receipts from pre-London nodes have no effectiveGasPrice
+            let gas_price = if receipt["effectiveGasPrice"].is_null() {
+                decimal(&transaction["gasPrice"])
+            } else {
+                decimal(&receipt["effectiveGasPrice"])
+            };
+            Ok::<_, Error>((
+                position,
+                serde_json::json!({
+                    "blockNumber": indexed.block_number.to_string(),
+                    "timeStamp": indexed.timestamp.to_string(),
+                    "hash": indexed.hash,
+                    "nonce": decimal(&transaction["nonce"]),
+                    "blockHash": indexed.block_hash,
+                    "transactionIndex": indexed.transaction_index.to_string(),
+                    "from": indexed.from,
+                    "to": receipt["to"].as_str().unwrap_or_default(),
+                    "value": indexed.value,
+                    "gas": decimal(&transaction["gas"]),
+                    "gasPrice": gas_price,
+                    "isError": if status == "0" { "1" } else { "0" },
+                    "txreceipt_status": status,
+                    "input": input,
+                    "contractAddress": receipt["contractAddress"].as_str().unwrap_or_default(),
+                    "cumulativeGasUsed": decimal(&receipt["cumulativeGasUsed"]),
+                    "gasUsed": decimal(&receipt["gasUsed"]),
+                    "confirmations": (head - indexed.block_number as i128 + 1).max(0).to_string(),
+                    "methodId": decoded.selector.unwrap_or_else(|| "0x".to_string()),
+                    "functionName": decoded.call.map(|c| c.signature).unwrap_or_default(),
+                }),
+            ))
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .try_collect()
+        .await?;
+    results.sort_by_key(|(position, _)| *position);
+    let results = results.into_iter().map(|(_, result)| result).collect();
+    Ok(Envelope::list(results, "No transactions found"))
+}
+
+async fn block(
+    action: &str,
+    params: &Params,
+    client: &EtherClient,
+    cache: &crate::Cache,
+) -> Result<Envelope, Error> {
+    match action {
+        "getblocknobytime" => {
+            let timestamp = number(params, "timestamp", 0)? as i128;
+            let after = params.get("closest").map(String::as_str) == Some("after");
+            let head = head(client, cache).await?;
+            // Last block at or before the timestamp, first one at or after it
+            let (mut low, mut high) = (0, head);
+            if block_timestamp(client, cache, 0).await? > timestamp {
+                high = -1;
+            }
+            while low < high {
+                let middle = low + (high - low + 1) / 2;
+                if block_timestamp(client, cache, middle).await? <= timestamp {
+                    low = middle;
+                } else {
+                    high = middle - 1;
+                }
+            }
+            let before = high;
+            let exact = before >= 0 && block_timestamp(client, cache, before).await? == timestamp;
+            let found = if after && !exact { before + 1 } else { before };
+            if found < 0 || found > head {
+                return Err(Error::NotFound("No closest block found".to_string()));
+            }
+            Ok(Envelope::ok(found.to_string().into()))
+        }
+        "getblockcountdown" => {
+            let target = number(params, "blockno", 0)? as i128;
+            let head = head(client, cache).await?;
+            if target <= head {
+                return Err(Error::Parse("Block number already pass".to_string()));
+            }
+            let sample = BLOCK_TIME_SAMPLE.min(head);
+            let block_time = if sample > 0 {
+                let elapsed = block_timestamp(client, cache, head).await?
+                    - block_timestamp(client, cache, head - sample).await?;
+                elapsed as f64 / sample as f64
+            } else {
+                0.0
+            };
+            let remaining = target - head;
+            Ok(Envelope::ok(serde_json::json!({
+                "CurrentBlock": head.to_string(),
+                "CountdownBlock": target.to_string(),
+                "RemainingBlock": remaining.to_string(),
+                "EstimateTimeInSec": format!("{:.1}", remaining as f64 * block_time),
+            })))
+        }
+        _ => Err(invalid_action()),
+    }
+}
+
+async fn transaction(
+    action: &str,
+    params: &Params,
+    client: &EtherClient,
+    cache: &crate::Cache,
+) -> Result<Envelope, Error> {
+    let tx_hash = format!("{:?}", hash(required(params, "txhash")?)?);
+    // Pending transactions have no receipt yet, their status is empty
+    let receipt = match request(
+        client,
+        cache,
+        "receipt",
+        serde_json::json!({ "tx": tx_hash }),
+    )
+    .await
+    {
+        Ok(receipt) => Some(receipt["receipt"].clone()),
+        Err(Error::NotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+    let status = receipt
+        .as_ref()
+        .map(|r| decimal(&r["status"]))
+        .unwrap_or_default();
+    match action {
+        "getstatus" => Ok(Envelope::ok(serde_json::json!({
+            "isError": if status == "0" { "1" } else { "0" },
+            "errDescription": if status == "0" { "Reverted" } else { "" },
+        }))),
+        "gettxreceiptstatus" => Ok(Envelope::ok(serde_json::json!({ "status": status }))),
+        _ => Err(invalid_action()),
+    }
+}
+
+/// Whether a log matches the `topicN` parameters, combined pairwise with the
+/// `topicN_M_opr` operators (`and` when missing)
+fn topics_match(log: &serde_json::Value, topics: &[Option<String>; 4], params: &Params) -> bool {
+    let matches: Vec<Option<bool>> = topics
+        .iter()
+        .enumerate()
+        .map(|(position, topic)| {
+            topic.as_ref().map(|topic| {
+                log["topics"][position]
+                    .as_str()
+                    .is_some_and(|t| t.eq_ignore_ascii_case(topic))
+            })
+        })
+        .collect();
+    let mut result = true;
+    for i in 0..4 {
+        for j in i + 1..4 {
+            if let (Some(a), Some(b)) = (matches[i], matches[j]) {
+                let or = params
+                    .get(&format!("topic{}_{}_opr", i, j))
+                    .map(String::as_str)
+                    == Some("or");
+                result &= if or { a || b } else { a && b };
+            }
+        }
+    }
+    // A single topic has no operator
+    if matches.iter().flatten().count() == 1 {
+        result = matches.iter().flatten().all(|m| *m);
+    }
+    result
+}
+
+async fn logs(
+    action: &str,
+    params: &Params,
+    client: &EtherClient,
+    cache: &crate::Cache,
+) -> Result<Envelope, Error> {
+    if action != "getLogs" {
+        return Err(invalid_action());
+    }
+    let block = |name: &str| match params.get(name).map(|v| v.trim()) {
+        None | Some("") | Some("latest") => Ok(None),
+        Some(v) => v
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| Error::Parse(format!("Invalid {} {}", name, v))),
+    };
+    let (from_block, to_block) = (block("fromBlock")?, block("toBlock")?);
+    let address = params
+        .get("address")
+        .map(|a| address(a).map(|a| format!("{:?}", a)))
+        .transpose()?;
+    let mut topics: [Option<String>; 4] = Default::default();
+    for (position, topic) in topics.iter_mut().enumerate() {
+        if let Some(value) = params.get(&format!("topic{}", position)) {
+            *topic = Some(format!("{:?}", hash(value)?));
+        }
+    }
+    if address.is_none() && topics.iter().all(Option::is_none) {
+        return Err(Error::Parse("Missing address or topic".to_string()));
+    }
+    // The node only combines topics with and, or operators are applied here
+    // to the logs of the address
+    let any_or = params.iter().any(|(k, v)| k.ends_with("_opr") && v == "or");
+    if any_or && address.is_none() {
+        return Err(Error::Parse("The or operators need an address".to_string()));
+    }
+    let (offset, count) = page(params, MAX_LOGS, MAX_LOGS, MAX_LOGS)?;
+
+    let node_topics: Vec<Option<String>> = if any_or { vec![] } else { topics.to_vec() };
+    let found = request(
+        client,
+        cache,
+        "logs",
+        serde_json::json!({
+            "fromBlock": from_block,
+            "toBlock": to_block,
+            "address": address,
+            "topics": node_topics,
+        }),
+    )
+    .await?;
+    let logs: Vec<serde_json::Value> = found["logs"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|log| topics_match(log, &topics, params))
+        .skip(offset)
+        .take(count)
+        .cloned()
+        .collect();
+
+    // Etherscan adds the time of the block, fetched once per block.
+    // The gas of the transactions would take a receipt per log, it is left out.
+    let block_numbers: BTreeSet<String> = logs
+        .iter()
+        .filter_map(|log| log["blockNumber"].as_str().map(str::to_string))
+        .collect();
+    let timestamps: HashMap<String, i128> = stream::iter(block_numbers)
+        .map(|block_number| async move {
+            let number = crate::json_value_hex_to_int(block_number.clone().into())?;
+            let timestamp = block_timestamp(client, cache, number).await?;
+            Ok::<_, Error>((block_number, timestamp))
+        })
+        .buffer_unordered(FETCH_CONCURRENCY)
+        .try_collect()
+        .await?;
+    let results = logs
+        .into_iter()
+        .map(|mut log| {
+            let timestamp = log["blockNumber"]
+                .as_str()
+                .and_then(|n| timestamps.get(n))
+                .copied()
+                .unwrap_or_default();
+            log["timeStamp"] = format!("0x{:x}", timestamp).into();
+            log
+        })
+        .collect();
+    Ok(Envelope::list(results, "No records found"))
+}
+
+async fn stats(
+    action: &str,
+    params: &Params,
+    client: &EtherClient,
+    cache: &crate::Cache,
+) -> Result<Envelope, Error> {
+    match action {
+        "tokensupply" => {
+            let contract = address(required(params, "contractaddress")?)?;
+            // totalSupply()
+            let supply = call_uint(client, cache, contract, "0x18160ddd".to_string()).await?;
+            Ok(Envelope::ok(supply.into()))
+        }
+        _ => Err(invalid_action()),
+    }
+}
+
+/// JSON-RPC methods of the proxy module, read-only ones only.
+/// Only the methods listed here reach the node.
+async fn proxy(action: &str, params: &Params, client: &EtherClient) -> serde_json::Value {
+    let call = || -> Result<Vec<serde_json::Value>, Error> {
+        let p = |name: &str| required(params, name).map(serde_json::Value::from);
+        Ok(match action {
+            "eth_blockNumber" | "eth_gasPrice" => vec![],
+            "eth_getBlockByNumber" => vec![
+                tag(params)?.into(),
+                (params.get("boolean").map(String::as_str) == Some("true")).into(),
+            ],
+            "eth_getUncleByBlockNumberAndIndex" | "eth_getTransactionByBlockNumberAndIndex" => {
+                vec![tag(params)?.into(), p("index")?]
+            }
+            "eth_getBlockTransactionCountByNumber" => vec![tag(params)?.into()],
+            "eth_getTransactionByHash" | "eth_getTransactionReceipt" => {
+                vec![format!("{:?}", hash(required(params, "txhash")?)?).into()]
+            }
+            "eth_getTransactionCount" | "eth_getCode" => vec![
+                format!("{:?}", address(required(params, "address")?)?).into(),
+                tag(params)?.into(),
+            ],
+            "eth_getStorageAt" => vec![
+                format!("{:?}", address(required(params, "address")?)?).into(),
+                p("position")?,
+                tag(params)?.into(),
+            ],
+            "eth_call" => vec![
+                serde_json::json!({ "to": p("to")?, "data": p("data")? }),
+                tag(params)?.into(),
+            ],
+            "eth_estimateGas" => {
+                let mut request = serde_json::Map::new();
+                for field in ["to", "data", "value", "gas", "gasPrice"] {
+                    if let Some(value) = params.get(field) {
+                        request.insert(field.to_string(), value.as_str().into());
+                    }
+                }
+                vec![request.into()]
+            }
+            _ => return Err(invalid_action()),
+        })
+    };
+
+    let result = match call() {
+        Ok(rpc_params) => client
+            .web3
+            .transport()
+            .execute(action, rpc_params)
+            .await
+            .map_err(Error::from),
+        // Wrong module parameters are reported like in the other modules
+        Err(e) => return serde_json::to_value(Envelope::error(e)).unwrap_or_default(),
+    };
+    match result {
+        Ok(result) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": result,
+        }),
+        Err(Error::Rpc(web3::Error::Rpc(e))) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": e.code.code(), "message": e.message },
+        }),
+        Err(e) => serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "error": { "code": -32000, "message": e.to_string() },
+        }),
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{parser, RequestData};
+use crate::cache::Category;
+use crate::error::Error;
+use crate::login::User;
+use crate::{parser, EtherClient, RequestData, State};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use rocket::serde::{Deserialize, Serialize};
 use rocket_dyn_templates::{context, Template};
 
@@ -33,77 +37,87 @@ pub struct ComplexBlock {
 pub async fn block(
     block_number: &str,
     with_tx: Option<String>,
-    redis_cache: &crate::State<crate::Cache>,
-) -> Template {
-    let b_n = block_number.parse::<i64>().unwrap();
+    user: Option<User>,
+    cache: &crate::State<crate::Cache>,
+    client: &State<EtherClient>,
+) -> Result<Template, Error> {
+    let b_n = block_number
+        .parse::<i64>()
+        .map_err(|_| Error::Parse(format!("Invalid block number {}", block_number)))?;
     let block = &parser::parse_request(
+        client,
+        cache,
         "eth",
         "block",
         RequestData {
             data: serde_json::json!({ "blockNumber": b_n }),
         },
     )
-    .await;
+    .await?;
 
     let mut result = block.data["block"].clone();
     let b_h = result["hash"].to_string();
     let mut transactions: Vec<SimpleTransaction> = Vec::new();
     let mut existing_cache = false;
-    if redis_cache.enabled {
-        existing_cache = crate::rcache::check_cache(
-            redis_cache.redis_client.clone().unwrap(),
-            &format!("blocktx_{}", b_h),
-        )
-        .unwrap();
+    if cache.enabled {
+        existing_cache = cache.backend()?.exists(&format!("blocktx_{}", b_h)).await?;
     }
     if with_tx.is_some() || existing_cache {
         transactions =
-            retrieve_transactions(result["transactions"].clone(), redis_cache, b_h).await;
+            retrieve_transactions(client, result["transactions"].clone(), cache, b_h).await?;
     } else {
         // could cache the transactions here anyway async
     }
 
-    result["transactions"] =
-        serde_json::from_str(&serde_json::to_string(&transactions).unwrap()).unwrap();
+    result["transactions"] = serde_json::to_value(&transactions)?;
 
-    Template::render("block", context! { block: result })
+    let labels = crate::userdata::for_page(cache, user.as_ref()).await.labels;
+    let names = crate::ens::names_for_page(client, cache, crate::ens::addresses_in(&result)).await;
+    Ok(Template::render(
+        "block",
+        context! { block: result, labels: labels, names: names },
+    ))
 }
 
 #[get("/block_hash/<block_hash>?<with_tx>")]
 pub async fn block_hash(
     block_hash: &str,
     with_tx: Option<String>,
-    redis_cache: &crate::State<crate::Cache>,
-) -> Template {
+    user: Option<User>,
+    cache: &crate::State<crate::Cache>,
+    client: &State<EtherClient>,
+) -> Result<Template, Error> {
     let b_h = crate::clean(block_hash.to_string());
     let block = &parser::parse_request(
+        client,
+        cache,
         "eth",
         "blockByHash",
         RequestData {
             data: serde_json::json!({ "blockHash": b_h }),
         },
     )
-    .await;
+    .await?;
 
     let mut result = block.data["block"].clone();
 
     let mut transactions: Vec<SimpleTransaction> = Vec::new();
     if with_tx.is_some()
-        || crate::rcache::check_cache(
-            redis_cache.redis_client.clone().unwrap(),
-            &format!("blocktx_{}", b_h),
-        )
-        .unwrap()
+        || (cache.enabled && cache.backend()?.exists(&format!("blocktx_{}", b_h)).await?)
     {
         transactions =
-            retrieve_transactions(result["transactions"].clone(), redis_cache, b_h).await;
+            retrieve_transactions(client, result["transactions"].clone(), cache, b_h).await?;
     } else {
         // could cache the transactions anyway here asyncronously
     }
-    result["transactions"] =
-        serde_json::from_str(&serde_json::to_string(&transactions).unwrap()).unwrap();
+    result["transactions"] = serde_json::to_value(&transactions)?;
 
-    Template::render("block", context! { block: result })
+    let labels = crate::userdata::for_page(cache, user.as_ref()).await.labels;
+    let names = crate::ens::names_for_page(client, cache, crate::ens::addresses_in(&result)).await;
+    Ok(Template::render(
+        "block",
+        context! { block: result, labels: labels, names: names },
+    ))
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -117,58 +131,65 @@ pub struct SimpleTransaction {
     pub block_hash: String,
 }
 
+/// Transactions of a block fetched from the node at the same time
+const TX_FETCH_CONCURRENCY: usize = 16;
+
 pub async fn retrieve_transactions(
+    client: &EtherClient,
     txs: serde_json::Value,
-    redis_cache: &crate::Cache,
+    cache: &crate::Cache,
     block_hash: String,
-) -> Vec<SimpleTransaction> {
+) -> Result<Vec<SimpleTransaction>, Error> {
     println!("  Retrieving transactions for block {}", block_hash);
-    let transactions = txs.as_array().unwrap();
-    let mut final_output: Vec<SimpleTransaction> = vec![];
+    let transactions = txs
+        .as_array()
+        .ok_or_else(|| Error::Parse(format!("No transactions in block {}", block_hash)))?;
 
-    if redis_cache.enabled {
-        let cache_result = crate::rcache::get(
-            redis_cache.redis_client.clone().unwrap(),
-            &format!("blocktx_{}", block_hash),
-        );
-        if cache_result.is_ok() {
-            let res = cache_result.unwrap();
-            if res != "" {
-                let cache_result = res;
-                let cache_result = serde_json::from_str(&cache_result).unwrap();
-                return cache_result;
-            }
+    if let Some(res) = cache.get(&format!("blocktx_{}", block_hash)).await? {
+        if !res.is_empty() {
+            return Ok(serde_json::from_str(&res)?);
         }
     }
 
-    for tx in transactions {
-        let t_h = crate::clean(tx.to_string());
-        let transaction = &parser::parse_request(
-            "eth",
-            "transaction",
-            RequestData {
-                data: serde_json::json!({ "tx": t_h }),
-            },
-        )
-        .await;
-
-        let result = transaction.data["transaction"].clone();
-        final_output.push(SimpleTransaction {
-            hash: crate::clean(result["hash"].to_string()),
-            from: crate::clean(result["from"].to_string()),
-            to: crate::clean(result["to"].to_string()),
-            value: crate::clean(result["value"].to_string()),
-            block_hash: crate::clean(result["blockHash"].to_string()),
-        });
-    }
-
-    if redis_cache.enabled {
-        let _ = crate::rcache::set(
-            redis_cache.redis_client.clone().unwrap(),
+    // Fetch several transactions at once, `buffered` keeps the order of the block
+    let hashes: Vec<String> = transactions
+        .iter()
+        .map(|tx| crate::clean(tx.to_string()))
+        .collect();
+    let final_output: Vec<SimpleTransaction> = stream::iter(hashes)
+        .map(|t_h| async move {
+            let transaction = &parser::parse_request(
+                client,
+                cache,
+                "eth",
+                "transaction",
+                RequestData {
+                    data: serde_json::json!({ "tx": t_h }),
+                },
+            )
+            .await?;
+
+            let result = transaction.data["transaction"].clone();
+            Ok::<_, Error>(SimpleTransaction {
+                hash: crate::clean(result["hash"].to_string()),
+                from: crate::clean(result["from"].to_string()),
+                to: crate::clean(result["to"].to_string()),
+                value: crate::clean(result["value"].to_string()),
+                block_hash: crate::clean(result["blockHash"].to_string()),
+            })
+        })
+        .buffered(TX_FETCH_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    // Keyed by block hash, the transactions of a block never change
+    cache
+        .set(
             &format!("blocktx_{}", block_hash),
-            &serde_json::to_string(&final_output).unwrap(),
-        );
-    }
+            &serde_json::to_string(&final_output)?,
+            Category::Final,
+        )
+        .await?;
 
-    final_output
+    Ok(final_output)
 }
@@ -0,0 +1,95 @@
+use crate::crawler::{CrawlOptions, CrawlStatus, CrawlerControl, Direction, MAX_CONCURRENCY};
+use crate::error::ApiError;
+use crate::login::Admin;
+use crate::{EtherClient, State};
+use rocket::serde::{json::Json, Deserialize};
+use rocket_dyn_templates::{context, Template};
+
+/// Range and settings of a crawl started from the admin page.
+/// Missing fields take the values given on the command line.
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct StartRequest {
+    pub start_block: Option<i64>,
+    pub end_block: Option<i64>,
+    pub direction: Option<Direction>,
+    /// Workers, at most `MAX_CONCURRENCY`
+    pub concurrency: Option<usize>,
+}
+
+/// Admin page following the crawler, with its controls
+#[get("/admin/crawler")]
+pub async fn crawler(
+    _admin: Admin,
+    control: &State<CrawlerControl>,
+    cache: &State<crate::Cache>,
+) -> Template {
+    Template::render(
+        "crawler",
+        context! {
+            status: control.status(),
+            concurrency: control.defaults.concurrency,
+            cache_enabled: cache.enabled,
+        },
+    )
+}
+
+#[get("/admin/crawler/status")]
+pub async fn status(_admin: Admin, control: &State<CrawlerControl>) -> Json<CrawlStatus> {
+    Json(control.status())
+}
+
+#[post(
+    "/admin/crawler/start",
+    format = "application/json",
+    data = "<request>"
+)]
+pub async fn start(
+    _admin: Admin,
+    request: Json<StartRequest>,
+    control: &State<CrawlerControl>,
+    client: &State<EtherClient>,
+    cache: &State<crate::Cache>,
+) -> Result<Json<CrawlStatus>, ApiError> {
+    let request = request.into_inner();
+    let defaults = &control.defaults;
+    let options = CrawlOptions {
+        start_block: request.start_block.or(defaults.start_block),
+        end_block: request.end_block.or(defaults.end_block),
+        direction: request.direction.unwrap_or(defaults.direction),
+        concurrency: request
+            .concurrency
+            .unwrap_or(defaults.concurrency)
+            .clamp(1, MAX_CONCURRENCY),
+        retries: defaults.retries,
+    };
+    control.start(client.inner().clone(), cache.inner().clone(), options)?;
+    Ok(Json(control.status()))
+}
+
+#[post("/admin/crawler/pause")]
+pub async fn pause(
+    _admin: Admin,
+    control: &State<CrawlerControl>,
+) -> Result<Json<CrawlStatus>, ApiError> {
+    control.pause()?;
+    Ok(Json(control.status()))
+}
+
+#[post("/admin/crawler/resume")]
+pub async fn resume(
+    _admin: Admin,
+    control: &State<CrawlerControl>,
+) -> Result<Json<CrawlStatus>, ApiError> {
+    control.resume()?;
+    Ok(Json(control.status()))
+}
+
+#[post("/admin/crawler/cancel")]
+pub async fn cancel(
+    _admin: Admin,
+    control: &State<CrawlerControl>,
+) -> Result<Json<CrawlStatus>, ApiError> {
+    control.cancel()?;
+    Ok(Json(control.status()))
+}
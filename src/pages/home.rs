@@ -1,9 +1,9 @@
-use crate::{json_value_hex_to_int, parser, RequestData};
+use crate::error::Error;
+use crate::{json_value_hex_to_int, parser, EtherClient, RequestData, State};
 use rocket::serde::{Deserialize, Serialize};
 use rocket_dyn_templates::{context, Template};
 
-#[derive(Debug, Clone, FromForm, Serialize, Deserialize)]
-#[cfg_attr(test, derive(PartialEq, UriDisplayQuery))]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct SimpleBlock {
     hash: String,
@@ -15,47 +15,68 @@ pub struct SimpleBlock {
 const LATEST_BLOCKS_AMOUNT: i128 = 20;
 
 #[get("/")]
-pub async fn index() -> Template {
+pub async fn index(
+    client: &State<EtherClient>,
+    cache: &State<crate::Cache>,
+) -> Result<Template, Error> {
     let block_number = &parser::parse_request(
+        client,
+        cache,
         "eth",
         "blockNumber",
         RequestData {
             data: serde_json::json!({}),
         },
     )
-    .await
+    .await?
     .data["blockNumber"];
-    let b_n = json_value_hex_to_int(block_number.clone());
+    let b_n = json_value_hex_to_int(block_number.clone())?;
 
-    let latest_blocks: Vec<SimpleBlock> = get_latest_blocks(b_n).await;
+    let latest_blocks: Vec<SimpleBlock> = get_latest_blocks(client, cache, b_n).await?;
 
-    Template::render("index", context! { blocks: latest_blocks })
+    Ok(Template::render(
+        "index",
+        context! { blocks: latest_blocks },
+    ))
 }
 
-pub async fn get_latest_blocks(b_n: i128) -> Vec<SimpleBlock> {
+pub async fn get_latest_blocks(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    b_n: i128,
+) -> Result<Vec<SimpleBlock>, Error> {
     let mut latest_blocks: Vec<SimpleBlock> = vec![];
     for i in 0..LATEST_BLOCKS_AMOUNT {
-        let block = &parser::parse_request(
-            "eth",
-            "block",
-            RequestData {
-                data: serde_json::json!({ "blockNumber": (b_n - i) as i64 }),
-            },
-        )
-        .await;
-        let block_hash = block.data["block"]["hash"].to_string();
-        let tx_amount = block.data["block"]["transactions"]
-            .as_array()
-            .unwrap()
-            .len() as i64;
-        let block_timestamp =
-            crate::json_value_hex_to_int(block.data["block"]["timestamp"].clone());
-        latest_blocks.push(SimpleBlock {
-            hash: crate::clean(block_hash.as_str().to_string()),
-            number: (b_n - i) as i64,
-            tx_amount: tx_amount,
-            human_date: block_timestamp as i64,
-        });
+        latest_blocks.push(simple_block(client, cache, (b_n - i) as i64).await?);
     }
-    return latest_blocks;
+    Ok(latest_blocks)
+}
+
+/// Summary of a block shown in the latest blocks table
+pub async fn simple_block(
+    client: &EtherClient,
+    cache: &crate::Cache,
+    block_number: i64,
+) -> Result<SimpleBlock, Error> {
+    let block = &parser::parse_request(
+        client,
+        cache,
+        "eth",
+        "block",
+        RequestData {
+            data: serde_json::json!({ "blockNumber": block_number }),
+        },
+    )
+    .await?;
+    let block_hash = block.data["block"]["hash"].to_string();
+    let tx_amount = block.data["block"]["transactions"]
+        .as_array()
+        .map_or(0, |txs| txs.len()) as i64;
+    let block_timestamp = crate::json_value_hex_to_int(block.data["block"]["timestamp"].clone())?;
+    Ok(SimpleBlock {
+        hash: crate::clean(block_hash.as_str().to_string()),
+        number: block_number,
+        tx_amount,
+        human_date: block_timestamp as i64,
+    })
 }
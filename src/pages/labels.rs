@@ -0,0 +1,26 @@
+use crate::error::ApiError;
+use crate::labels::{LabelRegistry, PublicLabel, RegistryStatus, MAX_SEARCH_RESULTS};
+use crate::login::Admin;
+use crate::State;
+use rocket::serde::json::Json;
+
+/// Public labels whose name or category matches `q`
+#[get("/labels/search?<q>")]
+pub async fn search(q: &str, registry: &State<LabelRegistry>) -> Json<Vec<PublicLabel>> {
+    Json(registry.search(q, MAX_SEARCH_RESULTS))
+}
+
+#[get("/admin/labels")]
+pub async fn status(_admin: Admin, registry: &State<LabelRegistry>) -> Json<RegistryStatus> {
+    Json(registry.status())
+}
+
+/// Load the label file now, without waiting for the change to be noticed
+#[post("/admin/labels/reload")]
+pub async fn reload(
+    _admin: Admin,
+    registry: &State<LabelRegistry>,
+) -> Result<Json<RegistryStatus>, ApiError> {
+    registry.reload()?;
+    Ok(Json(registry.status()))
+}
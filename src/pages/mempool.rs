@@ -0,0 +1,56 @@
+use crate::login::User;
+use crate::mempool::{Mempool, MempoolSnapshot};
+use crate::State;
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::json::Json;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::Shutdown;
+use rocket_dyn_templates::{context, Template};
+
+/// Pending and queued transactions per sender, with the gas price distribution
+#[get("/mempool")]
+pub async fn mempool(
+    mempool: &State<Mempool>,
+    user: Option<User>,
+    cache: &State<crate::Cache>,
+    client: &State<crate::EtherClient>,
+) -> Template {
+    let labels = crate::userdata::for_page(cache, user.as_ref()).await.labels;
+    let snapshot = mempool.snapshot();
+    let names = crate::ens::names_for_page(
+        client,
+        cache,
+        crate::ens::addresses_in(&serde_json::to_value(&snapshot).unwrap_or_default()),
+    )
+    .await;
+    Template::render(
+        "mempool",
+        context! { mempool: snapshot, labels: labels, names: names },
+    )
+}
+
+#[get("/mempool/snapshot")]
+pub async fn snapshot(mempool: &State<Mempool>) -> Json<MempoolSnapshot> {
+    Json(mempool.snapshot())
+}
+
+/// Sends the counts and gas prices on every refresh of the mempool
+#[get("/mempool/stream")]
+pub async fn stream(mempool: &State<Mempool>, mut end: Shutdown) -> EventStream![] {
+    let mut rx = mempool.subscribe();
+    EventStream! {
+        loop {
+            let msg = select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut end => break,
+            };
+
+            yield Event::json(&msg);
+        }
+    }
+}
@@ -1,4 +1,11 @@
 pub mod address;
+pub mod api;
 pub mod block;
+pub mod crawler;
 pub mod home;
+pub mod labels;
+pub mod mempool;
+pub mod search;
 pub mod transaction;
+pub mod user;
+pub mod watch;
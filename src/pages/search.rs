@@ -0,0 +1,272 @@
+use crate::error::{ApiError, Error};
+use crate::labels::{LabelRegistry, MAX_SEARCH_RESULTS};
+use crate::login::User;
+use crate::userdata::Label;
+use crate::{parser, EtherClient, RequestData, State};
+use rocket::response::Redirect;
+use rocket::serde::{json::Json, Serialize};
+use rocket_dyn_templates::{context, Template};
+use std::collections::BTreeMap;
+
+/// Something a search input designates
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SearchResult {
+    /// block, transaction, address, name, token or label
+    pub kind: &'static str,
+    /// The block number, hash or address, typing it in the search bar finds this result again
+    pub value: String,
+    pub description: String,
+    pub url: String,
+}
+
+/// A single result goes straight to its page, otherwise the results are listed
+#[derive(Responder)]
+pub enum SearchResponse {
+    Redirect(Redirect),
+    Page(Template),
+}
+
+#[get("/search?<q>")]
+pub async fn search(
+    q: Option<&str>,
+    user: Option<User>,
+    cache: &State<crate::Cache>,
+    client: &State<EtherClient>,
+    registry: &State<LabelRegistry>,
+) -> Result<SearchResponse, Error> {
+    let q = q.unwrap_or_default();
+    let labels = crate::userdata::for_page(cache, user.as_ref()).await.labels;
+    let mut results = classify(q, client, cache, registry, &labels).await?;
+    if results.len() == 1 {
+        return Ok(SearchResponse::Redirect(Redirect::to(
+            results.remove(0).url,
+        )));
+    }
+    Ok(SearchResponse::Page(Template::render(
+        "search_results",
+        context! { q: q.trim(), results: results },
+    )))
+}
+
+/// Results of a partial input, for the suggestions of the search bar
+#[get("/search/suggest?<q>")]
+pub async fn suggest(
+    q: &str,
+    user: Option<User>,
+    cache: &State<crate::Cache>,
+    client: &State<EtherClient>,
+    registry: &State<LabelRegistry>,
+) -> Result<Json<Vec<SearchResult>>, ApiError> {
+    let labels = crate::userdata::for_page(cache, user.as_ref()).await.labels;
+    Ok(Json(classify(q, client, cache, registry, &labels).await?))
+}
+
+/// Whatever the node found, None when it does not know the block or transaction
+fn found<T>(result: Result<T, Error>) -> Result<Option<T>, Error> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::NotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Find what an input designates.
+/// 64 hex digits are a block or a transaction hash (the node tells which), 40 hex digits
+/// an address, with or without 0x, other numbers block numbers. Anything else is looked up as an ENS name,
+/// a token symbol and a label, public or of the signed-in user.
+pub async fn classify(
+    q: &str,
+    client: &EtherClient,
+    cache: &crate::Cache,
+    registry: &LabelRegistry,
+    user_labels: &BTreeMap<String, Label>,
+) -> Result<Vec<SearchResult>, Error> {
+    let q = q.trim();
+    let hex = q
+        .strip_prefix("0x")
+        .or_else(|| q.strip_prefix("0X"))
+        .unwrap_or(q);
+    let is_hex = !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit());
+
+    if q.is_empty() {
+        Ok(vec![])
+    } else if is_hex && hex.len() == 64 {
+        hash(&format!("0x{}", hex.to_lowercase()), client, cache).await
+    } else if is_hex && hex.len() == 40 {
+        let address = format!("0x{}", hex.to_lowercase());
+        let description = match registry.get(&address) {
+            Some(label) => format!("Address ({})", label.name),
+            None => "Address".to_string(),
+        };
+        Ok(vec![SearchResult {
+            kind: "address",
+            url: format!("/address/{}", address),
+            value: address,
+            description,
+        }])
+    } else if q.chars().all(|c| c.is_ascii_digit()) {
+        block_number(q, client, cache).await
+    } else {
+        text(q, client, cache, registry, user_labels).await
+    }
+}
+
+async fn block_number(
+    q: &str,
+    client: &EtherClient,
+    cache: &crate::Cache,
+) -> Result<Vec<SearchResult>, Error> {
+    let number = match q.parse::<u64>() {
+        Ok(number) => number,
+        Err(_) => return Ok(vec![]),
+    };
+    let head = parser::parse_request(
+        client,
+        cache,
+        "eth",
+        "blockNumber",
+        RequestData {
+            data: serde_json::json!({}),
+        },
+    )
+    .await?;
+    let head = crate::json_value_hex_to_int(head.data["blockNumber"].clone())?;
+    if number as i128 > head {
+        return Ok(vec![]);
+    }
+    Ok(vec![SearchResult {
+        kind: "block",
+        value: number.to_string(),
+        description: format!("Block {}", number),
+        url: format!("/block/{}", number),
+    }])
+}
+
+/// Block and transaction hashes look the same, ask the node for both
+async fn hash(
+    hash: &str,
+    client: &EtherClient,
+    cache: &crate::Cache,
+) -> Result<Vec<SearchResult>, Error> {
+    let (transaction, block) = futures::join!(
+        parser::parse_request(
+            client,
+            cache,
+            "eth",
+            "transaction",
+            RequestData {
+                data: serde_json::json!({ "tx": hash }),
+            },
+        ),
+        parser::parse_request(
+            client,
+            cache,
+            "eth",
+            "blockByHash",
+            RequestData {
+                data: serde_json::json!({ "blockHash": hash }),
+            },
+        ),
+    );
+
+    let mut results = vec![];
+    if let Some(transaction) = found(transaction)? {
+        let pending = transaction.data["transaction"]["blockNumber"].is_null();
+        results.push(SearchResult {
+            kind: "transaction",
+            value: hash.to_string(),
+            description: if pending {
+                "Pending transaction".to_string()
+            } else {
+                "Transaction".to_string()
+            },
+            url: format!("/transaction/{}", hash),
+        });
+    }
+    if let Some(block) = found(block)? {
+        let number = crate::clean(block.data["block"]["number"].to_string());
+        results.push(SearchResult {
+            kind: "block",
+            value: hash.to_string(),
+            description: format!("Block {}", number),
+            url: format!("/block_hash/{}", hash),
+        });
+    }
+    Ok(results)
+}
+
+async fn text(
+    q: &str,
+    client: &EtherClient,
+    cache: &crate::Cache,
+    registry: &LabelRegistry,
+    user_labels: &BTreeMap<String, Label>,
+) -> Result<Vec<SearchResult>, Error> {
+    let mut results = vec![];
+
+    // A failing node should not hide the label matches
+    if q.contains('.') && !q.contains(char::is_whitespace) {
+        match crate::ens::resolve(client, cache, q).await {
+            Ok(Some(address)) => {
+                let name = q.to_lowercase();
+                // The address page shows the records of a name given in its URL
+                let plain = name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_');
+                results.push(SearchResult {
+                    kind: "name",
+                    url: if plain {
+                        format!("/address/{}", name)
+                    } else {
+                        format!("/address/{:?}", address)
+                    },
+                    description: format!("ENS name of {:?}", address),
+                    value: name,
+                })
+            }
+            Ok(None) => {}
+            Err(e) => println!("ENS name {} unavailable: {}", q, e),
+        }
+    }
+
+    for token in registry.by_symbol(q) {
+        results.push(SearchResult {
+            kind: "token",
+            url: format!("/address/{}", token.address),
+            description: format!("{} ({})", token.name, token.symbol.unwrap_or_default()),
+            value: token.address,
+        });
+    }
+
+    let query = q.to_lowercase();
+    let private = user_labels
+        .iter()
+        .filter(|(_, label)| label.name.to_lowercase().contains(&query));
+    for (address, label) in private {
+        results.push(SearchResult {
+            kind: "label",
+            value: address.clone(),
+            description: format!("{} (your label)", label.name),
+            url: format!("/address/{}", address),
+        });
+    }
+
+    for label in registry.search(q, MAX_SEARCH_RESULTS) {
+        results.push(SearchResult {
+            kind: "label",
+            url: format!("/address/{}", label.address),
+            description: match label.category {
+                Some(category) => format!("{} ({})", label.name, category),
+                None => label.name,
+            },
+            value: label.address,
+        });
+    }
+
+    // The same address can match as a token, a private and a public label
+    let mut seen = std::collections::HashSet::new();
+    results.retain(|r| seen.insert(r.url.clone()));
+    results.truncate(MAX_SEARCH_RESULTS);
+    Ok(results)
+}
@@ -1,4 +1,9 @@
-use crate::{parser, RequestData};
+use crate::cache::Category;
+use crate::decoder::AbiRegistry;
+use crate::error::Error;
+use crate::login::User;
+use crate::mempool::Mempool;
+use crate::{parser, EtherClient, RequestData, State};
 use rocket::serde::Serialize;
 use rocket_dyn_templates::{context, Template};
 
@@ -26,38 +31,168 @@ pub struct ComplexTransaction {
     pub value: String,
 }
 
+/// Fee paid by a mined transaction, amounts in ETH.
+/// The base fee (burnt) and priority tip are only split out for type-2 transactions.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[serde(crate = "rocket::serde")]
+pub struct FeeBreakdown {
+    pub fee: String,
+    pub base_fee: Option<String>,
+    pub priority_fee: Option<String>,
+}
+
 #[get("/transaction/<tx_hash>")]
-pub async fn transaction(tx_hash: &str, redis_cache: &crate::State<crate::Cache>) -> Template {
+pub async fn transaction(
+    tx_hash: &str,
+    cache: &crate::State<crate::Cache>,
+    client: &State<EtherClient>,
+    abi_registry: &State<AbiRegistry>,
+    mempool: &State<Mempool>,
+    user: Option<User>,
+) -> Result<Template, Error> {
     let t_h = crate::clean(tx_hash.to_string());
+    // Cached under the lowercase 0x hash, whatever the case of the URL
+    let key = t_h
+        .parse::<web3::types::H256>()
+        .map(|hash| format!("{:?}", hash))
+        .map_err(|_| Error::Parse(format!("Invalid transaction hash {}", t_h)))?;
 
-    if redis_cache.enabled {
-        if crate::rcache::check_cache(redis_cache.redis_client.clone().unwrap(), &t_h).unwrap() {
-            let r: serde_json::Value = serde_json::from_str(
-                &crate::rcache::get(redis_cache.redis_client.clone().unwrap(), &t_h).unwrap(),
+    let mut result: serde_json::Value = match cache.get(&key).await? {
+        Some(cached) => serde_json::from_str(&cached)?,
+        None => {
+            let transaction = &parser::parse_request(
+                client,
+                cache,
+                "eth",
+                "transaction",
+                RequestData {
+                    data: serde_json::json!({ "tx": t_h }),
+                },
             )
-            .unwrap();
-            return Template::render("transaction", context! { transaction: r });
+            .await?;
+
+            let result = transaction.data["transaction"].clone();
+
+            // Recent transactions can still move to another block, pending ones are not cached
+            let category = crate::clean(result["blockNumber"].to_string())
+                .parse::<u64>()
+                .ok()
+                .map(|n| {
+                    if cache.is_final(n) {
+                        Category::Final
+                    } else {
+                        Category::Recent
+                    }
+                });
+            if let Some(category) = category {
+                cache
+                    .set(&key, &serde_json::to_string(&result)?, category)
+                    .await?;
+            }
+            result
         }
+    };
+
+    abi_registry.annotate_transaction(&mut result)?;
+
+    // Pending transactions have no receipt yet, the mempool tells whether they wait for another nonce
+    let mut receipt = serde_json::Value::Null;
+    let mut fee = None;
+    let mut logs = vec![];
+    let mut pending = None;
+    if result["blockNumber"].is_null() {
+        pending = mempool.find(result["hash"].as_str().unwrap_or(&t_h));
+    } else {
+        receipt = parser::parse_request(
+            client,
+            cache,
+            "eth",
+            "receipt",
+            RequestData {
+                data: serde_json::json!({ "tx": t_h }),
+            },
+        )
+        .await?
+        .data["receipt"]
+            .clone();
+
+        let block = parser::parse_request(
+            client,
+            cache,
+            "eth",
+            "block",
+            RequestData {
+                data: serde_json::json!({ "blockNumber": result["blockNumber"] }),
+            },
+        )
+        .await?;
+        let base_fee_per_gas =
+            crate::json_value_hex_to_int(block.data["block"]["baseFeePerGas"].clone())
+                .ok()
+                .map(|b| web3::types::U256::from(b as u128));
+
+        fee = Some(fee_breakdown(&result, &receipt, base_fee_per_gas)?);
+        logs = abi_registry.decode_logs(&receipt);
     }
 
-    let transaction = &parser::parse_request(
-        "eth",
-        "transaction",
-        RequestData {
-            data: serde_json::json!({ "tx": t_h }),
-        },
+    let user_data = crate::userdata::for_page(cache, user.as_ref()).await;
+    let names = crate::ens::names_for_page(
+        client,
+        cache,
+        crate::ens::addresses_in(&serde_json::json!([result, receipt])),
     )
     .await;
+    Ok(Template::render(
+        "transaction",
+        context! {
+            transaction: result,
+            receipt: receipt,
+            fee: fee,
+            logs: logs,
+            pending: pending,
+            signed_in: user.is_some(),
+            watched: user_data.is_watched(&key),
+            labels: user_data.labels,
+            names: names,
+        },
+    ))
+}
+
+/// Compute the fee from the human readable transaction and receipt
+pub fn fee_breakdown(
+    transaction: &serde_json::Value,
+    receipt: &serde_json::Value,
+    base_fee_per_gas: Option<web3::types::U256>,
+) -> Result<FeeBreakdown, Error> {
+    let gas_used = dec_value(&receipt["gasUsed"])?;
+    // Receipts from pre-London nodes have no effectiveGasPrice
+    let gas_price = if receipt["effectiveGasPrice"].is_null() {
+        dec_value(&transaction["gasPrice"])?
+    } else {
+        dec_value(&receipt["effectiveGasPrice"])?
+    };
 
-    let result = transaction.data["transaction"].clone();
+    let mut breakdown = FeeBreakdown {
+        fee: crate::wei_to_eth(gas_used * gas_price),
+        base_fee: None,
+        priority_fee: None,
+    };
 
-    if redis_cache.enabled {
-        let _ = crate::rcache::set(
-            redis_cache.redis_client.clone().unwrap(),
-            &t_h,
-            &serde_json::to_string(&result).unwrap(),
-        );
+    if let (Some(base_fee_per_gas), Some("0x2")) = (base_fee_per_gas, transaction["type"].as_str())
+    {
+        let tip_per_gas = gas_price.saturating_sub(base_fee_per_gas);
+        breakdown.base_fee = Some(crate::wei_to_eth(gas_used * base_fee_per_gas));
+        breakdown.priority_fee = Some(crate::wei_to_eth(gas_used * tip_per_gas));
     }
 
-    Template::render("transaction", context! { transaction: result })
+    Ok(breakdown)
+}
+
+/// Parse a decimal string produced by parser::response_to_human_readable
+fn dec_value(v: &serde_json::Value) -> Result<web3::types::U256, Error> {
+    let s = v
+        .as_str()
+        .ok_or_else(|| Error::Parse(format!("Expected a number, got {}", v)))?;
+    web3::types::U256::from_dec_str(s).map_err(|_| Error::Parse(format!("Invalid number {}", s)))
 }
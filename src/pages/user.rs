@@ -0,0 +1,148 @@
+use crate::error::{ApiError, Error};
+use crate::login::User;
+use crate::userdata::{Label, UserData};
+use crate::{Cache, State};
+use rocket::http::Header;
+use rocket::serde::{json::Json, Deserialize};
+use rocket_dyn_templates::{context, Template};
+
+#[derive(Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WatchRequest {
+    /// Address or transaction hash
+    pub target: String,
+    pub note: Option<String>,
+}
+
+/// A download of the user's data
+#[derive(Responder)]
+#[response(content_type = "json")]
+pub struct Export {
+    body: String,
+    disposition: Header<'static>,
+}
+
+/// Watchlists and labels of the signed-in user
+#[get("/user")]
+pub async fn user(
+    user: Option<User>,
+    cache: &State<Cache>,
+    client: &State<crate::EtherClient>,
+) -> Template {
+    let data = crate::userdata::for_page(cache, user.as_ref()).await;
+    let names = crate::ens::names_for_page(
+        client,
+        cache,
+        crate::ens::addresses_in(&serde_json::to_value(&data).unwrap_or_default()),
+    )
+    .await;
+    Template::render(
+        "user",
+        context! {
+            user: user,
+            data: data,
+            names: names,
+            cache_enabled: cache.enabled,
+        },
+    )
+}
+
+#[get("/user/data")]
+pub async fn data(user: User, cache: &State<Cache>) -> Result<Json<UserData>, ApiError> {
+    Ok(Json(UserData::load(cache, &user).await?))
+}
+
+/// The user's data as a JSON file, it can be imported back
+#[get("/user/export")]
+pub async fn export(user: User, cache: &State<Cache>) -> Result<Export, ApiError> {
+    let data = UserData::load(cache, &user).await?;
+    Ok(Export {
+        body: serde_json::to_string_pretty(&data).map_err(Error::from)?,
+        disposition: Header::new(
+            "Content-Disposition",
+            format!("attachment; filename=\"etherglass-{}.json\"", user.address),
+        ),
+    })
+}
+
+/// Merge an export into the user's data, or replace it with `?replace=true`
+#[post(
+    "/user/import?<replace>",
+    format = "application/json",
+    data = "<imported>"
+)]
+pub async fn import(
+    imported: Json<UserData>,
+    replace: Option<bool>,
+    user: User,
+    cache: &State<Cache>,
+) -> Result<Json<UserData>, ApiError> {
+    let mut data = if replace.unwrap_or(false) {
+        UserData::default()
+    } else {
+        UserData::load(cache, &user).await?
+    };
+    data.import(imported.into_inner())?;
+    data.save(cache, &user).await?;
+    Ok(Json(data))
+}
+
+#[post(
+    "/user/watchlists/<list>",
+    format = "application/json",
+    data = "<request>"
+)]
+pub async fn watch(
+    list: &str,
+    request: Json<WatchRequest>,
+    user: User,
+    cache: &State<Cache>,
+) -> Result<Json<UserData>, ApiError> {
+    let request = request.into_inner();
+    let mut data = UserData::load(cache, &user).await?;
+    data.watch(list, &request.target, request.note)?;
+    data.save(cache, &user).await?;
+    Ok(Json(data))
+}
+
+#[delete("/user/watchlists/<list>/<target>")]
+pub async fn unwatch(
+    list: &str,
+    target: &str,
+    user: User,
+    cache: &State<Cache>,
+) -> Result<Json<UserData>, ApiError> {
+    let mut data = UserData::load(cache, &user).await?;
+    data.unwatch(list, target)?;
+    data.save(cache, &user).await?;
+    Ok(Json(data))
+}
+
+#[put(
+    "/user/labels/<address>",
+    format = "application/json",
+    data = "<label>"
+)]
+pub async fn label(
+    address: &str,
+    label: Json<Label>,
+    user: User,
+    cache: &State<Cache>,
+) -> Result<Json<UserData>, ApiError> {
+    let mut data = UserData::load(cache, &user).await?;
+    data.label(address, label.into_inner())?;
+    data.save(cache, &user).await?;
+    Ok(Json(data))
+}
+
+#[delete("/user/labels/<address>")]
+pub async fn unlabel(
+    address: &str,
+    user: User,
+    cache: &State<Cache>,
+) -> Result<Json<UserData>, ApiError> {
+    let mut data = UserData::load(cache, &user).await?;
+    data.unlabel(address)?;
+    data.save(cache, &user).await?;
+    Ok(Json(data))
+}
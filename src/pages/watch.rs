@@ -0,0 +1,187 @@
+use crate::error::Error;
+use crate::follower::BlockActivity;
+use crate::pages::block::SimpleTransaction;
+use crate::{Cache, EtherClient, State};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::Serialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{error::RecvError, Sender};
+use rocket::Shutdown;
+use web3::types::{BlockNumber, TransactionId, H160, H256};
+
+/// Something that happened to a watched address
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AddressEvent {
+    /// A transaction sent (`out`), received (`in`) or sent to itself (`self`) by the address
+    Transaction {
+        direction: &'static str,
+        block_number: u64,
+        transaction: SimpleTransaction,
+    },
+    /// Balance in ETH at a block, sent on connection and whenever it changes
+    Balance { block_number: u64, balance: String },
+}
+
+/// Where a watched transaction stands, sent on connection and whenever it changes
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TransactionEvent {
+    pub hash: String,
+    /// `pending`, `success`, `failed` or `dropped` when the node no longer knows it
+    pub status: &'static str,
+    pub block_number: Option<u64>,
+    /// Blocks on top of the transaction's block, itself included
+    pub confirmations: u64,
+    /// Deep enough not to be reorged, the stream ends after it
+    #[serde(rename = "final")]
+    pub is_final: bool,
+}
+
+/// Sends the transactions of an address and its balance changes as blocks arrive
+#[get("/watch/address/<address>")]
+pub async fn address(
+    address: &str,
+    activity: &State<Sender<BlockActivity>>,
+    client: &State<EtherClient>,
+    mut end: Shutdown,
+) -> Result<EventStream![], Error> {
+    let watched = address
+        .parse::<H160>()
+        .map_err(|_| Error::Parse(format!("Invalid address {}", address)))?;
+    let address = format!("{:?}", watched);
+    let client = client.inner().clone();
+    let mut rx = activity.subscribe();
+    let head = client.web3.eth().block_number().await?.as_u64();
+    let mut balance = client.web3.eth().balance(watched, None).await?;
+
+    Ok(EventStream! {
+        yield Event::json(&AddressEvent::Balance {
+            block_number: head,
+            balance: crate::wei_to_eth(balance),
+        });
+        loop {
+            let block = select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut end => break,
+            };
+
+            for transaction in block.transactions {
+                let direction = match (transaction.from == address, transaction.to == address) {
+                    (true, true) => "self",
+                    (true, false) => "out",
+                    (false, true) => "in",
+                    (false, false) => continue,
+                };
+                yield Event::json(&AddressEvent::Transaction {
+                    direction,
+                    block_number: block.number,
+                    transaction,
+                });
+            }
+
+            // Balances also move without a transaction of the address, e.g. internal transfers
+            let at = Some(BlockNumber::Number(block.number.into()));
+            match client.web3.eth().balance(watched, at).await {
+                Ok(new_balance) if new_balance != balance => {
+                    balance = new_balance;
+                    yield Event::json(&AddressEvent::Balance {
+                        block_number: block.number,
+                        balance: crate::wei_to_eth(balance),
+                    });
+                }
+                Ok(_) => {}
+                Err(e) => println!("Balance of {} at {} unavailable: {}", address, block.number, e),
+            }
+        }
+    })
+}
+
+/// Sends the confirmations and status of a transaction as blocks arrive, until it is final
+#[get("/watch/tx/<tx_hash>")]
+pub async fn transaction(
+    tx_hash: &str,
+    activity: &State<Sender<BlockActivity>>,
+    client: &State<EtherClient>,
+    cache: &State<Cache>,
+    mut end: Shutdown,
+) -> Result<EventStream![], Error> {
+    let hash = tx_hash
+        .parse::<H256>()
+        .map_err(|_| Error::Parse(format!("Invalid transaction hash {}", tx_hash)))?;
+    let client = client.inner().clone();
+    let confirmations = cache.confirmations;
+    let mut rx = activity.subscribe();
+    let head = client.web3.eth().block_number().await?.as_u64();
+    let mut last = transaction_event(&client, hash, head, confirmations).await?;
+    if last.status == "dropped" {
+        return Err(Error::NotFound(format!("Transaction {:?}", hash)));
+    }
+
+    Ok(EventStream! {
+        yield Event::json(&last);
+        while !last.is_final {
+            let block = select! {
+                msg = rx.recv() => match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Closed) => break,
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut end => break,
+            };
+
+            // A receipt can move to another block or disappear after a reorg, ask again every block
+            match transaction_event(&client, hash, block.head, confirmations).await {
+                Ok(event) if event != last => {
+                    last = event;
+                    yield Event::json(&last);
+                }
+                Ok(_) => {}
+                Err(e) => println!("Status of {:?} unavailable: {}", hash, e),
+            }
+        }
+    })
+}
+
+/// Status of a transaction with `head` as the latest block
+async fn transaction_event(
+    client: &EtherClient,
+    hash: H256,
+    head: u64,
+    confirmations: u64,
+) -> Result<TransactionEvent, Error> {
+    let mut event = TransactionEvent {
+        hash: format!("{:?}", hash),
+        status: "pending",
+        block_number: None,
+        confirmations: 0,
+        is_final: false,
+    };
+    let eth = client.web3.eth();
+    let mined = eth
+        .transaction_receipt(hash)
+        .await?
+        .and_then(|receipt| Some((receipt.block_number?.as_u64(), receipt.status)));
+    match mined {
+        Some((block_number, status)) => {
+            event.status = match status.map(|s| s.as_u64()) {
+                Some(0) => "failed",
+                _ => "success",
+            };
+            event.block_number = Some(block_number);
+            event.confirmations = (head + 1).saturating_sub(block_number);
+            event.is_final = block_number + confirmations <= head;
+        }
+        None => {
+            if eth.transaction(TransactionId::Hash(hash)).await?.is_none() {
+                event.status = "dropped";
+            }
+        }
+    }
+    Ok(event)
+}
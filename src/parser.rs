@@ -1,170 +1,304 @@
 use rocket::serde::Serialize;
 use std::str::FromStr;
 
+use crate::cache::Category;
+use crate::error::Error;
+
+/// Most blocks a `logs` request searches
+pub const MAX_LOG_BLOCKS: u64 = 10_000;
+
 #[derive(Serialize, Clone)]
 #[serde(crate = "rocket::serde")]
 pub struct ResponseData {
     pub data: serde_json::Value,
 }
 
-pub async fn parse_request(api: &str, method: &str, data: crate::RequestData) -> ResponseData {
-    let client = crate::client().await.unwrap();
-
-    // This whole cache should be coming from the Rocket handler, not here.
-    let redis_cache = crate::Cache {
-        enabled: *crate::REDIS_CACHE.lock().unwrap(),
-
-        // Temporary redefining it here, should be moved around coming from the Rocket handler
-        redis_client: Some(redis::Client::open("redis://localhost:6379").unwrap()),
-    };
-
+pub async fn parse_request(
+    client: &crate::EtherClient,
+    cache: &crate::Cache,
+    api: &str,
+    method: &str,
+    data: crate::RequestData,
+) -> Result<ResponseData, Error> {
     let result = match api {
         "eth" => match method {
             "balance" => {
-                let input_address = data.data["address"].as_str().unwrap();
-                let mut _balance = web3::types::U256::from(0 as i128);
-                let mut _address = web3::types::H160::from([0u8; 20]);
-                if input_address.contains(".eth") {
-                    _address = client.ens.eth_address(input_address).await.unwrap();
-                    _balance = client.web3.eth().balance(_address, None).await.unwrap();
+                let input_address = input_str(&data, "address")?;
+                // Any name is resolved through ENS (.eth, .xyz, DNS names…), addresses have no dot
+                let address = if input_address.contains('.') {
+                    let address = client.ens.eth_address(input_address).await.map_err(|e| {
+                        Error::NotFound(format!("ENS name {} ({})", input_address, e))
+                    })?;
+                    if address.is_zero() {
+                        return Err(Error::NotFound(format!("ENS name {}", input_address)));
+                    }
+                    address
                 } else {
-                    _address = input_address.parse().unwrap();
-                    _balance = client.web3.eth().balance(_address, None).await.unwrap();
+                    input_address
+                        .parse::<web3::types::H160>()
+                        .map_err(|_| Error::Parse(format!("Invalid address {}", input_address)))?
+                };
+                let key = format!("balance_{:?}", address);
+                let balance = match cache.get(&key).await? {
+                    Some(balance) => serde_json::from_str(&balance)?,
+                    None => {
+                        let balance = client.web3.eth().balance(address, None).await?;
+                        cache
+                            .set(&key, &serde_json::to_string(&balance)?, Category::Account)
+                            .await?;
+                        balance
+                    }
+                };
+                ResponseData {
+                    data: serde_json::json!({
+                        "balance": balance,
+                        "address": format!("{:?}", address)
+                    }),
                 }
+            }
+            "nonce" => {
+                let input_address = input_str(&data, "address")?;
+                let address = input_address
+                    .parse::<web3::types::H160>()
+                    .map_err(|_| Error::Parse(format!("Invalid address {}", input_address)))?;
+                let key = format!("nonce_{:?}", address);
+                let nonce = match cache.get(&key).await? {
+                    Some(nonce) => serde_json::from_str(&nonce)?,
+                    None => {
+                        let nonce = client.web3.eth().transaction_count(address, None).await?;
+                        cache
+                            .set(&key, &serde_json::to_string(&nonce)?, Category::Account)
+                            .await?;
+                        nonce
+                    }
+                };
                 ResponseData {
                     data: serde_json::json!({
-                        "balance": _balance,
-                        "address":_address.to_string()
+                        "nonce": nonce,
+                        "address": format!("{:?}", address)
                     }),
                 }
             }
             "blockNumber" => {
-                let block_number = client.web3.eth().block_number().await.unwrap();
+                let block_number = client.web3.eth().block_number().await?;
+                cache.observe_head(block_number.as_u64());
                 ResponseData {
                     data: serde_json::json!({ "blockNumber": block_number }),
                 }
             }
             "block" => {
-                let block_number = &data.data["blockNumber"]
-                    .clone()
-                    .to_string()
+                let block_number = crate::clean(data.data["blockNumber"].to_string())
                     .parse::<u64>()
-                    .unwrap();
-                let web3_block_number = web3::types::BlockNumber::from(*block_number as i64);
-
-                if redis_cache.enabled {
-                    if crate::rcache::check_cache(
-                        redis_cache.redis_client.clone().unwrap(),
-                        &block_number.to_string(),
-                    )
-                    .unwrap()
-                    {
-                        let cached_block = crate::rcache::get(
-                            redis_cache.redis_client.clone().unwrap(),
-                            &format!("block_{}", block_number),
-                        )
-                        .unwrap();
-                        return ResponseData {
-                            data: serde_json::from_str(&cached_block).unwrap(),
-                        };
-                    };
+                    .map_err(|_| {
+                        Error::Parse(format!("Invalid block number {}", data.data["blockNumber"]))
+                    })?;
+                let web3_block_number = web3::types::BlockNumber::from(block_number);
+                let canonical_key = crate::cache::canonical_key(block_number);
+
+                // Blocks are cached by hash, the number only points to the canonical hash
+                if let Some(hash) = cache.get(&canonical_key).await? {
+                    if let Some(cached_block) = cached(cache, &format!("block_{}", hash)).await? {
+                        return Ok(response_to_human_readable(ResponseData {
+                            data: serde_json::json!({ "block": cached_block }),
+                        }));
+                    }
                 }
 
                 let block = client
                     .web3
                     .eth()
                     .block(web3::types::BlockId::from(web3_block_number))
-                    .await
-                    .unwrap();
+                    .await?
+                    .ok_or_else(|| Error::NotFound(format!("Block {}", block_number)))?;
 
-                if redis_cache.enabled {
-                    let _ = crate::rcache::set(
-                        redis_cache.redis_client.clone().unwrap(),
-                        &format!("block_{}", block_number),
-                        &serde_json::to_string(&block).unwrap(),
-                    );
+                if let Some(hash) = block.hash {
+                    let hash = format!("{:?}", hash);
+                    // A block hash always designates the same block
+                    cache
+                        .set(
+                            &format!("block_{}", hash),
+                            &serde_json::to_string(&block)?,
+                            Category::Final,
+                        )
+                        .await?;
+                    // Recent blocks can still be replaced by a reorg, their hash is recorded
+                    // by the reorg detection. Canonical hashes never expire nor get evicted.
+                    if cache.is_final(block_number) {
+                        cache.set_pinned(&canonical_key, &hash).await?;
+                    }
                 }
                 ResponseData {
                     data: serde_json::json!({ "block": block }),
                 }
             }
             "blockByHash" => {
-                let block_hash = &crate::clean(data.data["blockHash"].clone().to_string())
+                let block_hash = crate::clean(data.data["blockHash"].to_string())
                     .parse::<web3::types::H256>()
-                    .unwrap();
-
-                if redis_cache.enabled {
-                    if crate::rcache::check_cache(
-                        redis_cache.redis_client.clone().unwrap(),
-                        &block_hash.to_string(),
-                    )
-                    .unwrap()
-                    {
-                        let cached_block = crate::rcache::get(
-                            redis_cache.redis_client.clone().unwrap(),
-                            &format!("block_{}", block_hash.to_string()),
-                        )
-                        .unwrap();
-                        return ResponseData {
-                            data: serde_json::from_str(&cached_block).unwrap(),
-                        };
-                    };
+                    .map_err(|_| {
+                        Error::Parse(format!("Invalid block hash {}", data.data["blockHash"]))
+                    })?;
+                let key = format!("block_{:?}", block_hash);
+
+                if let Some(cached_block) = cached(cache, &key).await? {
+                    return Ok(response_to_human_readable(ResponseData {
+                        data: serde_json::json!({ "block": cached_block }),
+                    }));
                 }
 
                 let block = client
                     .web3
                     .eth()
-                    .block(web3::types::BlockId::Hash(*block_hash))
-                    .await
-                    .unwrap();
+                    .block(web3::types::BlockId::Hash(block_hash))
+                    .await?
+                    .ok_or_else(|| Error::NotFound(format!("Block {:?}", block_hash)))?;
 
-                if redis_cache.enabled {
-                    let _ = crate::rcache::set(
-                        redis_cache.redis_client.clone().unwrap(),
-                        &format!("block_{}", block_hash.to_string()),
-                        &serde_json::to_string(&block).unwrap(),
-                    );
-                }
+                cache
+                    .set(&key, &serde_json::to_string(&block)?, Category::Final)
+                    .await?;
 
                 ResponseData {
                     data: serde_json::json!({ "block": block }),
                 }
             }
             "transaction" => {
-                let tx_hash =
-                    web3::types::H256::from_str(data.data["tx"].as_str().unwrap()).unwrap();
+                let input_hash = input_str(&data, "tx")?;
+                let tx_hash = web3::types::H256::from_str(input_hash).map_err(|_| {
+                    Error::Parse(format!("Invalid transaction hash {}", input_hash))
+                })?;
                 let transaction = client
                     .web3
                     .eth()
                     .transaction(web3::types::TransactionId::Hash(tx_hash))
-                    .await
-                    .unwrap();
+                    .await?
+                    .ok_or_else(|| Error::NotFound(format!("Transaction {:?}", tx_hash)))?;
                 ResponseData {
                     data: serde_json::json!({ "transaction": transaction }),
                 }
             }
+            "receipt" => {
+                let input_hash = input_str(&data, "tx")?;
+                let tx_hash = web3::types::H256::from_str(input_hash).map_err(|_| {
+                    Error::Parse(format!("Invalid transaction hash {}", input_hash))
+                })?;
+                let receipt = client
+                    .web3
+                    .eth()
+                    .transaction_receipt(tx_hash)
+                    .await?
+                    .ok_or_else(|| Error::NotFound(format!("Receipt for {:?}", tx_hash)))?;
+                ResponseData {
+                    data: serde_json::json!({ "receipt": receipt }),
+                }
+            }
+            "logs" => {
+                let block = |field: &str| -> Result<Option<u64>, Error> {
+                    match &data.data[field] {
+                        serde_json::Value::Null => Ok(None),
+                        v => v
+                            .as_u64()
+                            .map(Some)
+                            .ok_or_else(|| Error::Parse(format!("Invalid {} {}", field, v))),
+                    }
+                };
+                let (from_block, to_block) = (block("fromBlock")?, block("toBlock")?);
+                // Missing blocks are the head
+                let head = if from_block.is_none() || to_block.is_none() {
+                    let head = client.web3.eth().block_number().await?.as_u64();
+                    cache.observe_head(head);
+                    head
+                } else {
+                    0
+                };
+                let (from_block, to_block) = (from_block.unwrap_or(head), to_block.unwrap_or(head));
+                if to_block.saturating_sub(from_block) >= MAX_LOG_BLOCKS {
+                    return Err(Error::Parse(format!(
+                        "Block range is too large, at most {} blocks",
+                        MAX_LOG_BLOCKS
+                    )));
+                }
+                let mut filter = web3::types::FilterBuilder::default()
+                    .from_block(web3::types::BlockNumber::from(from_block))
+                    .to_block(web3::types::BlockNumber::from(to_block));
+                if let Some(address) = data.data["address"].as_str() {
+                    let address = address
+                        .parse::<web3::types::H160>()
+                        .map_err(|_| Error::Parse(format!("Invalid address {}", address)))?;
+                    filter = filter.address(vec![address]);
+                }
+                // One optional topic per position, all of them have to match
+                let mut topics: [Option<Vec<web3::types::H256>>; 4] = Default::default();
+                for (position, topic) in data.data["topics"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .enumerate()
+                    .take(4)
+                {
+                    if let Some(topic) = topic.as_str() {
+                        let topic = topic
+                            .parse::<web3::types::H256>()
+                            .map_err(|_| Error::Parse(format!("Invalid topic {}", topic)))?;
+                        topics[position] = Some(vec![topic]);
+                    }
+                }
+                let [t0, t1, t2, t3] = topics;
+                filter = filter.topics(t0, t1, t2, t3);
+                let logs = client.web3.eth().logs(filter.build()).await?;
+                ResponseData {
+                    data: serde_json::json!({ "logs": logs }),
+                }
+            }
+            "call" => {
+                let to = input_str(&data, "to")?;
+                let to = to
+                    .parse::<web3::types::H160>()
+                    .map_err(|_| Error::Parse(format!("Invalid address {}", to)))?;
+                let input = input_str(&data, "data")?;
+                let input = hex::decode(input.trim_start_matches("0x"))
+                    .map_err(|_| Error::Parse(format!("Invalid call data {}", input)))?;
+                let request = web3::types::CallRequest {
+                    to: Some(to),
+                    data: Some(input.into()),
+                    ..Default::default()
+                };
+                let result = client.web3.eth().call(request, None).await?;
+                ResponseData {
+                    data: serde_json::json!({ "result": result }),
+                }
+            }
             "syncing" => {
-                let syncing = client.web3.eth().syncing().await.unwrap();
+                let syncing = client.web3.eth().syncing().await?;
                 ResponseData {
                     data: serde_json::json!({ "syncing": syncing }),
                 }
             }
             "chainId" => {
-                let chain_id = client.web3.eth().chain_id().await.unwrap();
+                let chain_id = client.web3.eth().chain_id().await?;
                 ResponseData {
                     data: serde_json::json!({ "chainId": chain_id }),
                 }
             }
-            _ => ResponseData {
-                data: serde_json::json!({}),
-            },
-        },
-        _ => ResponseData {
-            data: serde_json::json!({}),
+            _ => return Err(Error::NotFound(format!("Method {}/{}", api, method))),
         },
+        _ => return Err(Error::NotFound(format!("Api {}", api))),
     };
 
-    response_to_human_readable(result)
+    Ok(response_to_human_readable(result))
+}
+
+/// Read a string field from the request data
+fn input_str<'a>(data: &'a crate::RequestData, field: &str) -> Result<&'a str, Error> {
+    data.data[field]
+        .as_str()
+        .ok_or_else(|| Error::Parse(format!("Missing {}", field)))
+}
+
+/// Return the cached JSON value under `key`, if the cache is enabled and has it
+async fn cached(cache: &crate::Cache, key: &str) -> Result<Option<serde_json::Value>, Error> {
+    match cache.get(key).await? {
+        Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+        None => Ok(None),
+    }
 }
 
 pub fn response_to_human_readable(res: ResponseData) -> ResponseData {
@@ -173,65 +307,64 @@ pub fn response_to_human_readable(res: ResponseData) -> ResponseData {
     let mut data = r.data;
     let data_iter = data.clone();
 
-    for (key, _value) in data_iter.as_object().unwrap() {
+    let keys = match data_iter.as_object() {
+        Some(keys) => keys,
+        None => return res,
+    };
+
+    for key in keys.keys() {
         let strkey = key.as_str();
         match strkey {
             "block" => {
-                for (k, v) in data_iter["block"].as_object().unwrap() {
+                for (k, v) in data_iter["block"].as_object().into_iter().flatten() {
                     let str_k = k.as_str();
                     match str_k {
                         "gasUsed" => {
-                            data["block"]["gasUsed"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["block"]["gasUsed"] = hex_to_dec(v);
                         }
                         "gasLimit" => {
-                            data["block"]["gasLimit"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["block"]["gasLimit"] = hex_to_dec(v);
                         }
                         "number" => {
-                            data["block"]["number"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["block"]["number"] = hex_to_dec(v);
                         }
                         _ => {}
                     }
                 }
             }
             "transaction" => {
-                for (k, v) in data_iter["transaction"].as_object().unwrap() {
+                for (k, v) in data_iter["transaction"].as_object().into_iter().flatten() {
                     let str_k = k.as_str();
                     match str_k {
                         "value" => {
-                            data["transaction"]["value"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["transaction"]["value"] = hex_to_dec(v);
                         }
                         "blockNumber" => {
-                            data["transaction"]["blockNumber"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["transaction"]["blockNumber"] = hex_to_dec(v);
                         }
                         "gas" => {
-                            data["transaction"]["gas"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["transaction"]["gas"] = hex_to_dec(v);
                         }
                         "gasPrice" => {
-                            data["transaction"]["gasPrice"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["transaction"]["gasPrice"] = hex_to_dec(v);
                         }
                         "transactionIndex" => {
-                            data["transaction"]["transactionIndex"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["transaction"]["transactionIndex"] = hex_to_dec(v);
                         }
                         "nonce" => {
-                            data["transaction"]["nonce"] = serde_json::Value::from(
-                                crate::json_value_hex_to_int(v.clone()).to_string(),
-                            );
+                            data["transaction"]["nonce"] = hex_to_dec(v);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            "receipt" => {
+                for (k, v) in data_iter["receipt"].as_object().into_iter().flatten() {
+                    let str_k = k.as_str();
+                    match str_k {
+                        "blockNumber" | "transactionIndex" | "status" | "gasUsed"
+                        | "cumulativeGasUsed" | "effectiveGasPrice" => {
+                            data["receipt"][str_k] = hex_to_dec(v);
                         }
                         _ => {}
                     }
@@ -243,5 +376,14 @@ pub fn response_to_human_readable(res: ResponseData) -> ResponseData {
 
     r.data = data;
 
-    return r;
+    r
+}
+
+/// Hex quantity as a decimal string, values that are not hex quantities
+/// (e.g. a null blockNumber on a pending transaction) are kept as they are
+fn hex_to_dec(v: &serde_json::Value) -> serde_json::Value {
+    match crate::json_value_hex_to_int(v.clone()) {
+        Ok(n) => serde_json::Value::from(n.to_string()),
+        Err(_) => v.clone(),
+    }
 }
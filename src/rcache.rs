@@ -1,23 +1,323 @@
-use redis::Commands;
-
-pub fn set(client: redis::Client, key: &String, value: &String) -> redis::RedisResult<isize> {
-    println!("Caching {}", key);
-    let mut con = client.get_connection()?;
-    // throw away the result, just make sure it does not fail
-    let _: () = con.set(key, value)?;
-    // read back the key and return it.  Because the return value
-    // from the function is a result for integer this will automatically
-    // convert into one.
-    con.get(key)
+use crate::cache::{BackendStats, CacheBackend, CacheOp};
+use crate::error::Error;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, IntoConnectionInfo};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Connection settings of the Redis backend, from the command line or the environment
+#[derive(clap::Args, Debug, Clone)]
+pub struct RedisConfig {
+    /// Redis server, `redis://[:password@]host[:port][/db]`, `rediss://` for TLS
+    #[clap(
+        long = "redis-url",
+        env = "REDIS_URL",
+        default_value = "redis://localhost:6379"
+    )]
+    pub url: String,
+    /// Password, overrides the one in the URL
+    #[clap(long = "redis-password", env = "REDIS_PASSWORD")]
+    pub password: Option<String>,
+    /// Connect with TLS even when the URL scheme is `redis://`
+    #[clap(long = "redis-tls", env = "REDIS_TLS")]
+    pub tls: bool,
+    /// Database number, overrides the one in the URL
+    #[clap(long = "redis-db", env = "REDIS_DB")]
+    pub db: Option<i64>,
+    /// Prefix added to every key (e.g. `mainnet:`), so several instances can share a server
+    #[clap(
+        long = "redis-key-prefix",
+        env = "REDIS_KEY_PREFIX",
+        default_value = ""
+    )]
+    pub key_prefix: String,
+    /// Connections kept open to the server
+    #[clap(long = "redis-pool-size", env = "REDIS_POOL_SIZE", default_value_t = 4)]
+    pub pool_size: usize,
+}
+
+impl RedisConfig {
+    /// The URL with the password, TLS and database overrides applied
+    pub fn connection_info(&self) -> Result<redis::ConnectionInfo, Error> {
+        let mut info = self.url.as_str().into_connection_info()?;
+        if let Some(password) = &self.password {
+            info.redis.password = Some(password.clone());
+        }
+        if let Some(db) = self.db {
+            info.redis.db = db;
+        }
+        if self.tls {
+            if let redis::ConnectionAddr::Tcp(host, port) = info.addr {
+                info.addr = redis::ConnectionAddr::TcpTls {
+                    host,
+                    port,
+                    insecure: false,
+                };
+            }
+        }
+        Ok(info)
+    }
 }
 
-pub fn get(client: redis::Client, key: &String) -> redis::RedisResult<String> {
-    println!("Retrieving {}", key);
-    let mut con = client.get_connection()?;
-    con.get(key)
+/// Sorted set of the cached keys by write time, kept when there is a size budget
+const CACHED_KEYS: &str = "cached_keys";
+/// Hash of the size of every cached key and value, kept when there is a size budget
+const CACHED_SIZES: &str = "cached_sizes";
+/// Cached keys checked at once by `maintain`
+const MAINTAIN_CHUNK: isize = 1000;
+
+/// Redis backend. Field maps are Redis hashes and scored sets are sorted sets.
+/// Requests are spread over a pool of multiplexed connections that reconnect on their own.
+///
+/// The server configuration is left alone, other instances and other data can share it.
+/// With a `max_bytes` budget the cached node responses are tracked in `cached_keys`
+/// and `cached_sizes`, and `maintain` deletes the least recently written ones
+/// while they are over the budget. Values stored with `set` and `batch` are never evicted.
+pub struct RedisCache {
+    pool: Vec<ConnectionManager>,
+    next: AtomicUsize,
+    prefix: String,
+    /// 0 for no limit
+    max_bytes: u64,
+    /// Size of the cached values, as of the last maintenance
+    cached_bytes: AtomicU64,
+    evicted: AtomicU64,
 }
 
-pub fn check_cache(client: redis::Client, key: &String) -> redis::RedisResult<bool> {
-    let mut con = client.get_connection()?;
-    con.exists(key)
+impl RedisCache {
+    pub async fn open(config: &RedisConfig, max_bytes: u64) -> Result<RedisCache, Error> {
+        let client = redis::Client::open(config.connection_info()?)?;
+        let mut pool = vec![];
+        for _ in 0..config.pool_size.max(1) {
+            pool.push(ConnectionManager::new(client.clone()).await?);
+        }
+        Ok(RedisCache {
+            pool,
+            next: AtomicUsize::new(0),
+            prefix: config.key_prefix.clone(),
+            max_bytes,
+            cached_bytes: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue the end of the tracking of a key that is no longer a cached value
+    fn untrack(&self, pipe: &mut redis::Pipeline, key: &str) {
+        if self.max_bytes > 0 {
+            pipe.zrem(self.key(CACHED_KEYS), key)
+                .ignore()
+                .hdel(self.key(CACHED_SIZES), key)
+                .ignore();
+        }
+    }
+
+    fn connection(&self) -> ConnectionManager {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.pool[i % self.pool.len()].clone()
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+#[async_trait::async_trait]
+impl CacheBackend for RedisCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, Error> {
+        println!("Retrieving {}", key);
+        Ok(self.connection().get(self.key(key)).await?)
+    }
+
+    async fn set(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error> {
+        self.batch(vec![CacheOp::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+            ttl,
+        }])
+        .await
+    }
+
+    async fn cache(&self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), Error> {
+        println!("Caching {}", key);
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        match ttl {
+            Some(ttl) => pipe.set_ex(self.key(key), value, ttl.as_secs().max(1) as usize),
+            None => pipe.set(self.key(key), value),
+        }
+        .ignore();
+        if self.max_bytes > 0 {
+            let written = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            pipe.zadd(self.key(CACHED_KEYS), key, written)
+                .ignore()
+                .hset(self.key(CACHED_SIZES), key, key.len() + value.len())
+                .ignore();
+        }
+        Ok(pipe.query_async(&mut self.connection()).await?)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(self.connection().exists(self.key(key)).await?)
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.batch(vec![CacheOp::Delete {
+            key: key.to_string(),
+        }])
+        .await
+    }
+
+    async fn ttl(&self, key: &str) -> Result<Option<Duration>, Error> {
+        // -1 when the key never expires, -2 when it does not exist
+        let ttl: i64 = self.connection().ttl(self.key(key)).await?;
+        Ok((ttl >= 0).then(|| Duration::from_secs(ttl as u64)))
+    }
+
+    async fn batch(&self, ops: Vec<CacheOp>) -> Result<(), Error> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for op in &ops {
+            match op {
+                CacheOp::Set { key, .. } | CacheOp::Delete { key } => self.untrack(&mut pipe, key),
+                _ => {}
+            }
+            match op {
+                CacheOp::Set { key, value, ttl } => match ttl {
+                    Some(ttl) => pipe.set_ex(self.key(key), value, ttl.as_secs().max(1) as usize),
+                    None => pipe.set(self.key(key), value),
+                },
+                CacheOp::Delete { key } => pipe.del(self.key(key)),
+                CacheOp::SetFields { key, fields } => pipe.hset_multiple(self.key(key), fields),
+                CacheOp::AddScored { key, member, score } => {
+                    pipe.zadd(self.key(key), member, *score)
+                }
+                CacheOp::RemoveScored { key, member } => pipe.zrem(self.key(key), member),
+            }
+            .ignore();
+        }
+        Ok(pipe.query_async(&mut self.connection()).await?)
+    }
+
+    async fn fields(&self, keys: &[String]) -> Result<Vec<HashMap<String, String>>, Error> {
+        let mut pipe = redis::pipe();
+        for key in keys {
+            pipe.hgetall(self.key(key));
+        }
+        Ok(pipe.query_async(&mut self.connection()).await?)
+    }
+
+    async fn range_by_score(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+        ascending: bool,
+        offset: usize,
+        count: usize,
+    ) -> Result<Vec<String>, Error> {
+        let mut con = self.connection();
+        let (offset, count) = (offset as isize, count as isize);
+        Ok(if ascending {
+            con.zrangebyscore_limit(self.key(key), min, max, offset, count)
+                .await?
+        } else {
+            con.zrevrangebyscore_limit(self.key(key), max, min, offset, count)
+                .await?
+        })
+    }
+
+    async fn count_by_score(&self, key: &str, min: f64, max: f64) -> Result<usize, Error> {
+        Ok(self.connection().zcount(self.key(key), min, max).await?)
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        // SCAN does not block the server like KEYS
+        let mut con = self.connection();
+        let mut iter: redis::AsyncIter<String> =
+            con.scan_match(format!("{}*", self.key(prefix))).await?;
+        let mut keys = vec![];
+        while let Some(key) = iter.next_item().await {
+            keys.push(key[self.prefix.len()..].to_string());
+        }
+        Ok(keys)
+    }
+
+    async fn stats(&self) -> Result<BackendStats, Error> {
+        let mut con = self.connection();
+        let keys: u64 = redis::cmd("DBSIZE").query_async(&mut con).await?;
+        let info: String = redis::cmd("INFO").query_async(&mut con).await?;
+        // Memory is that of the whole server
+        let used_memory = info
+            .lines()
+            .find_map(|line| line.strip_prefix("used_memory:"))
+            .and_then(|value| value.trim().parse().ok())
+            .unwrap_or(0);
+        Ok(BackendStats {
+            keys,
+            bytes: used_memory,
+            cached_bytes: self.cached_bytes.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        })
+    }
+
+    async fn maintain(&self) -> Result<(), Error> {
+        if self.max_bytes == 0 {
+            return Ok(());
+        }
+        let mut con = self.connection();
+        // Cached keys still there with their size, oldest first. Expired ones are untracked.
+        let mut cached: Vec<(String, u64)> = vec![];
+        let mut start = 0;
+        loop {
+            let keys: Vec<String> = con
+                .zrange(self.key(CACHED_KEYS), start, start + MAINTAIN_CHUNK - 1)
+                .await?;
+            if keys.is_empty() {
+                break;
+            }
+            let mut exists = redis::pipe();
+            for key in &keys {
+                exists.exists(self.key(key));
+            }
+            let exists: Vec<bool> = exists.query_async(&mut con).await?;
+            let sizes: Vec<Option<u64>> = redis::cmd("HMGET")
+                .arg(self.key(CACHED_SIZES))
+                .arg(&keys)
+                .query_async(&mut con)
+                .await?;
+            let mut expired = redis::pipe();
+            let mut untracked = 0;
+            for ((key, exists), size) in keys.into_iter().zip(exists).zip(sizes) {
+                if exists {
+                    cached.push((key, size.unwrap_or_default()));
+                } else {
+                    self.untrack(&mut expired, &key);
+                    untracked += 1;
+                }
+            }
+            if untracked > 0 {
+                expired.query_async::<_, ()>(&mut con).await?;
+            }
+            // The untracked keys shift the next ones down
+            start += MAINTAIN_CHUNK - untracked;
+        }
+
+        let mut bytes: u64 = cached.iter().map(|(_, size)| size).sum();
+        for (key, size) in cached {
+            if bytes <= self.max_bytes {
+                break;
+            }
+            let mut pipe = redis::pipe();
+            pipe.atomic().del(self.key(&key)).ignore();
+            self.untrack(&mut pipe, &key);
+            pipe.query_async::<_, ()>(&mut con).await?;
+            bytes = bytes.saturating_sub(size);
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        self.cached_bytes.store(bytes, Ordering::Relaxed);
+        Ok(())
+    }
 }
@@ -0,0 +1,58 @@
+use crate::cache::{canonical_key, Cache};
+use crate::error::Error;
+
+/// Follow a new head: record the canonical hash of the recent blocks and detect reorgs.
+///
+/// Walks back from the head, comparing the hash the node has at each height with the one
+/// recorded in the cache, until both agree or the block is final. Every height whose
+/// recorded hash changed has its address index rolled back.
+/// Returns these heights, oldest first, so the caller can index them again.
+pub async fn track_head(
+    client: &crate::EtherClient,
+    cache: &Cache,
+    head: u64,
+) -> Result<Vec<u64>, Error> {
+    cache.observe_head(head);
+    let backend = cache.backend()?;
+    let mut reorged = vec![];
+
+    let mut block_number = head;
+    loop {
+        let hash = match client
+            .web3
+            .eth()
+            .block(web3::types::BlockId::from(web3::types::BlockNumber::from(
+                block_number,
+            )))
+            .await?
+            .and_then(|b| b.hash)
+        {
+            Some(hash) => format!("{:?}", hash),
+            // The head moved back between the calls, the next poll will catch up
+            None => break,
+        };
+
+        let key = canonical_key(block_number);
+        match backend.get(&key).await? {
+            Some(recorded) if recorded == hash => break,
+            Some(recorded) => {
+                println!(
+                    "Reorg at block {}: {} replaced by {}",
+                    block_number, recorded, hash
+                );
+                crate::pages::address::rollback_indexed_block(cache, block_number as i64).await?;
+                reorged.push(block_number);
+            }
+            None => {}
+        }
+        backend.set(&key, &hash, None).await?;
+
+        if block_number == 0 || cache.is_final(block_number) {
+            break;
+        }
+        block_number -= 1;
+    }
+
+    reorged.reverse();
+    Ok(reorged)
+}
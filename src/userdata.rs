@@ -0,0 +1,257 @@
+use crate::cache::CacheOp;
+use crate::error::Error;
+use crate::login::User;
+use crate::Cache;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use web3::types::{H160, H256};
+
+/// What a watchlist entry points to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+#[serde(rename_all = "lowercase")]
+pub enum WatchKind {
+    Address,
+    Transaction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct WatchEntry {
+    pub kind: WatchKind,
+    /// Lowercase address or transaction hash
+    pub target: String,
+    #[serde(default)]
+    pub note: Option<String>,
+    /// Unix time it was added
+    #[serde(default)]
+    pub added_at: i64,
+}
+
+/// A private name tag on an address
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Label {
+    pub name: String,
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// Everything a signed-in user saved, also the import/export format
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UserData {
+    /// Watchlists by name
+    #[serde(default)]
+    pub watchlists: BTreeMap<String, Vec<WatchEntry>>,
+    /// Labels by lowercase address
+    #[serde(default)]
+    pub labels: BTreeMap<String, Label>,
+}
+
+/// Longest label name, note or watchlist name accepted
+const MAX_TEXT: usize = 256;
+
+/// Most entries a user can keep, the entries of all the watchlists and the labels counted apart
+const MAX_ENTRIES: usize = 10_000;
+
+/// Field map of a user's data, it never expires
+fn user_key(address: &str) -> String {
+    format!("userdata_{}", address.to_lowercase())
+}
+
+/// Kind and normalized form of an address or a transaction hash
+pub fn watch_target(target: &str) -> Result<(WatchKind, String), Error> {
+    let target = target.trim();
+    if let Ok(address) = target.parse::<H160>() {
+        Ok((WatchKind::Address, format!("{:?}", address)))
+    } else if let Ok(hash) = target.parse::<H256>() {
+        Ok((WatchKind::Transaction, format!("{:?}", hash)))
+    } else {
+        Err(Error::Parse(format!(
+            "{} is neither an address nor a transaction hash",
+            target
+        )))
+    }
+}
+
+/// Lowercase 0x address
+pub fn normalize_address(address: &str) -> Result<String, Error> {
+    address
+        .trim()
+        .parse::<H160>()
+        .map(|a| format!("{:?}", a))
+        .map_err(|_| Error::Parse(format!("Invalid address {}", address)))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+fn check_text(what: &str, text: &str) -> Result<(), Error> {
+    if text.trim().is_empty() || text.len() > MAX_TEXT {
+        return Err(Error::Parse(format!(
+            "The {} must have 1 to {} characters",
+            what, MAX_TEXT
+        )));
+    }
+    Ok(())
+}
+
+impl UserData {
+    pub async fn load(cache: &Cache, user: &User) -> Result<UserData, Error> {
+        let fields = cache
+            .backend()?
+            .fields(&[user_key(&user.address)])
+            .await?
+            .pop()
+            .unwrap_or_default();
+        let mut data = UserData::default();
+        if let Some(watchlists) = fields.get("watchlists") {
+            data.watchlists = serde_json::from_str(watchlists)?;
+        }
+        if let Some(labels) = fields.get("labels") {
+            data.labels = serde_json::from_str(labels)?;
+        }
+        Ok(data)
+    }
+
+    pub async fn save(&self, cache: &Cache, user: &User) -> Result<(), Error> {
+        cache
+            .backend()?
+            .batch(vec![CacheOp::SetFields {
+                key: user_key(&user.address),
+                fields: vec![
+                    (
+                        "watchlists".to_string(),
+                        serde_json::to_string(&self.watchlists)?,
+                    ),
+                    ("labels".to_string(), serde_json::to_string(&self.labels)?),
+                ],
+            }])
+            .await
+    }
+
+    /// Add a target to a watchlist, updating the note when it is already there
+    pub fn watch(&mut self, list: &str, target: &str, note: Option<String>) -> Result<(), Error> {
+        check_text("watchlist name", list)?;
+        let note = note.filter(|note| !note.trim().is_empty());
+        if let Some(note) = &note {
+            check_text("note", note)?;
+        }
+        let (kind, target) = watch_target(target)?;
+        let list = list.trim();
+        let entry = self
+            .watchlists
+            .get_mut(list)
+            .and_then(|entries| entries.iter_mut().find(|e| e.target == target));
+        if let Some(entry) = entry {
+            entry.note = note;
+            return Ok(());
+        }
+        if self.watchlists.values().map(Vec::len).sum::<usize>() >= MAX_ENTRIES {
+            return Err(Error::Conflict("Too many watchlist entries".to_string()));
+        }
+        self.watchlists
+            .entry(list.to_string())
+            .or_default()
+            .push(WatchEntry {
+                kind,
+                target,
+                note,
+                added_at: now(),
+            });
+        Ok(())
+    }
+
+    /// Remove a target from a watchlist, the list goes away with its last entry
+    pub fn unwatch(&mut self, list: &str, target: &str) -> Result<(), Error> {
+        let (_, target) = watch_target(target)?;
+        let list = list.trim();
+        let entries = self
+            .watchlists
+            .get_mut(list)
+            .ok_or_else(|| Error::NotFound(format!("Watchlist {}", list)))?;
+        entries.retain(|e| e.target != target);
+        if entries.is_empty() {
+            self.watchlists.remove(list);
+        }
+        Ok(())
+    }
+
+    pub fn label(&mut self, address: &str, mut label: Label) -> Result<(), Error> {
+        check_text("label", &label.name)?;
+        label.note = label.note.filter(|note| !note.trim().is_empty());
+        if let Some(note) = &label.note {
+            check_text("note", note)?;
+        }
+        let address = normalize_address(address)?;
+        if !self.labels.contains_key(&address) && self.labels.len() >= MAX_ENTRIES {
+            return Err(Error::Conflict("Too many labels".to_string()));
+        }
+        self.labels.insert(address, label);
+        Ok(())
+    }
+
+    pub fn unlabel(&mut self, address: &str) -> Result<(), Error> {
+        let address = normalize_address(address)?;
+        self.labels
+            .remove(&address)
+            .map(|_| ())
+            .ok_or_else(|| Error::NotFound(format!("Label of {}", address)))
+    }
+
+    /// Add the watchlists and labels of an export, the imported ones win on conflicts.
+    /// Every entry is checked as if it was added one by one, and added before now.
+    pub fn import(&mut self, imported: UserData) -> Result<(), Error> {
+        for (list, entries) in imported.watchlists {
+            for entry in entries {
+                let (_, target) = watch_target(&entry.target)?;
+                if !(0..=now()).contains(&entry.added_at) {
+                    return Err(Error::Parse(format!(
+                        "Invalid time {} for {}",
+                        entry.added_at, target
+                    )));
+                }
+                self.watch(&list, &target, entry.note)?;
+                // Keep when it was first added
+                let added = self
+                    .watchlists
+                    .get_mut(list.trim())
+                    .and_then(|entries| entries.iter_mut().find(|e| e.target == target));
+                if let (Some(added), true) = (added, entry.added_at > 0) {
+                    added.added_at = entry.added_at;
+                }
+            }
+        }
+        for (address, label) in imported.labels {
+            self.label(&address, label)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a target is in any watchlist
+    pub fn is_watched(&self, target: &str) -> bool {
+        let target = target.to_lowercase();
+        self.watchlists
+            .values()
+            .flatten()
+            .any(|entry| entry.target == target)
+    }
+}
+
+/// Data of the signed-in user for the templates, empty when signed out.
+/// Pages still render when it cannot be read.
+pub async fn for_page(cache: &Cache, user: Option<&User>) -> UserData {
+    let user = match user {
+        Some(user) if cache.enabled => user,
+        _ => return UserData::default(),
+    };
+    UserData::load(cache, user).await.unwrap_or_else(|e| {
+        println!("Data of {} unavailable: {}", user.address, e);
+        UserData::default()
+    })
+}